@@ -4,15 +4,23 @@ use std::io::Write;
 use std::path::PathBuf;
 
 fn main() {
+    // Pick the memory layout matching the selected platform
+    let memory: &[u8] = if env::var_os("CARGO_FEATURE_PLATFORM_DUET_WIFI").is_some() {
+        include_bytes!("memory-sam4e8e.x")
+    } else {
+        include_bytes!("memory.x")
+    };
+
     // Put the linker script somewhere the linker can find it
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
-    // Only re-run the build script when memory.x is changed,
+    // Only re-run the build script when a memory layout is changed,
     // instead of when any part of the source code changes.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-sam4e8e.x");
 }
@@ -0,0 +1,7 @@
+/* Linker script for the ATSAM4E8E found on the Duet WiFi */
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  FLASH : ORIGIN = 0x00400000, LENGTH = 512K
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+}
@@ -0,0 +1,409 @@
+//! Geometry of the G2/G3 arcs.
+//!
+//! An arc is drawn in the selected plane and may move the remaining axis linearly, tracing a
+//! helix. Its center is given either by offsets from the start point (I, J and K for the X, Y and
+//! Z axes) or by a radius (R), a negative radius selecting the arc longer than half a turn. The
+//! motion runs arcs as a series of chords deviating from the arc by no more than a tolerance.
+
+use core::f32::consts::PI;
+
+use crate::gcode::processor::ArcDirection;
+use crate::state::Plane;
+
+/// Arcs whose angle is within this of a full turn, in radians, are considered full circles.
+const ANGULAR_EPSILON: f32 = 5e-7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// Neither the center offsets nor the radius were given.
+    MissingCenter,
+    /// Both the center offsets and the radius were given.
+    CenterAndRadius,
+    /// The center is as far as the start point, where the arc would have no radius.
+    ZeroRadius,
+    /// The start and end points are not at the same distance from the center.
+    RadiusMismatch { start: f32, end: f32 },
+    /// The radius is shorter than half the distance between the start and end points.
+    RadiusTooSmall { radius: f32, distance: f32 },
+    /// The start and end points are the same with a radius given: there is no way to tell where
+    /// the center is.
+    UndefinedCenter,
+}
+
+/// Indexes in `[x, y, z]` of the two axes of `plane`, in the order the rotation is measured, then
+/// of the axis normal to it.
+pub fn axes(plane: Plane) -> (usize, usize, usize) {
+    match plane {
+        Plane::XY => (0, 1, 2),
+        Plane::XZ => (2, 0, 1),
+        Plane::YZ => (1, 2, 0),
+    }
+}
+
+/// Resolves the center of an arc going from `start` to `end`, as offsets from `start`.
+///
+/// `offsets` are the I, J and K words of which only the ones of the plane's axes are used, and
+/// `radius` is the R word.
+pub fn center(
+    plane: Plane,
+    direction: ArcDirection,
+    start: [f32; 3],
+    end: [f32; 3],
+    offsets: [Option<f32>; 3],
+    radius: Option<f32>,
+) -> Result<[f32; 3], Error> {
+    let (a0, a1, _) = axes(plane);
+    let (dx, dy) = (end[a0] - start[a0], end[a1] - start[a1]);
+    let mut center = [0.; 3];
+
+    match (offsets[a0].or(offsets[a1]), radius) {
+        (None, None) => return Err(Error::MissingCenter),
+        (Some(_), Some(_)) => return Err(Error::CenterAndRadius),
+        (Some(_), None) => {
+            let (i, j) = (offsets[a0].unwrap_or(0.), offsets[a1].unwrap_or(0.));
+            let start_radius = libm::hypotf(i, j);
+            let end_radius = libm::hypotf(dx - i, dy - j);
+            if start_radius == 0. {
+                return Err(Error::ZeroRadius);
+            }
+            // Tolerate the rounding of the numbers the center and end points were given with.
+            let mismatch = libm::fabsf(start_radius - end_radius);
+            if mismatch > 0.005 && (mismatch > 0.5 || mismatch > 0.001 * start_radius) {
+                return Err(Error::RadiusMismatch {
+                    start: start_radius,
+                    end: end_radius,
+                });
+            }
+            center[a0] = i;
+            center[a1] = j;
+        }
+        (None, Some(r)) => {
+            let distance = libm::hypotf(dx, dy);
+            if distance == 0. {
+                return Err(Error::UndefinedCenter);
+            }
+            let h2 = 4. * r * r - distance * distance;
+            if h2 < 0. {
+                return Err(Error::RadiusTooSmall {
+                    radius: libm::fabsf(r),
+                    distance,
+                });
+            }
+            // Distance from the middle of the chord to the center, relative to the chord's
+            // length, on the side given by the direction and the sign of the radius.
+            let mut h = -libm::sqrtf(h2) / distance;
+            if direction == ArcDirection::CounterClockwise {
+                h = -h;
+            }
+            if r < 0. {
+                h = -h;
+            }
+            center[a0] = 0.5 * (dx - dy * h);
+            center[a1] = 0.5 * (dy + dx * h);
+        }
+    }
+    Ok(center)
+}
+
+/// An arc, possibly a helix, in a plane.
+pub struct Arc {
+    plane: Plane,
+    start: [f32; 3],
+    end: [f32; 3],
+    /// Center, in absolute coordinates.
+    center: [f32; 3],
+    radius: f32,
+    /// Angle of the start point around the center.
+    start_angle: f32,
+    /// Signed angle travelled, positive counter-clockwise.
+    sweep: f32,
+}
+
+impl Arc {
+    /// `offsets` locate the center relative to `start`, as resolved by [`center`].
+    pub fn new(
+        plane: Plane,
+        direction: ArcDirection,
+        start: [f32; 3],
+        end: [f32; 3],
+        offsets: [f32; 3],
+    ) -> Self {
+        let (a0, a1, _) = axes(plane);
+        let mut center = start;
+        center[a0] += offsets[a0];
+        center[a1] += offsets[a1];
+
+        let (sx, sy) = (-offsets[a0], -offsets[a1]);
+        let (ex, ey) = (end[a0] - center[a0], end[a1] - center[a1]);
+        let mut sweep = libm::atan2f(sx * ey - sy * ex, sx * ex + sy * ey);
+        match direction {
+            ArcDirection::Clockwise if sweep >= -ANGULAR_EPSILON => sweep -= 2. * PI,
+            ArcDirection::CounterClockwise if sweep <= ANGULAR_EPSILON => sweep += 2. * PI,
+            _ => {}
+        }
+
+        Self {
+            plane,
+            start,
+            end,
+            center,
+            radius: libm::hypotf(sx, sy),
+            start_angle: libm::atan2f(sy, sx),
+            sweep,
+        }
+    }
+
+    /// Number of chords needed for none of them to deviate from the arc by more than `tolerance`.
+    pub fn chords(&self, tolerance: f32) -> u32 {
+        // Angle of a chord whose sagitta is the tolerance.
+        let angle = if tolerance < self.radius {
+            2. * libm::acosf(1. - tolerance / self.radius)
+        } else {
+            PI
+        };
+        (libm::ceilf(libm::fabsf(self.sweep) / angle) as u32).max(1)
+    }
+
+    /// The end point of the `n`th chord out of `count`.
+    pub fn point(&self, n: u32, count: u32) -> [f32; 3] {
+        if n >= count {
+            return self.end;
+        }
+        let (a0, a1, normal) = axes(self.plane);
+        let ratio = n as f32 / count as f32;
+        let angle = self.start_angle + self.sweep * ratio;
+        let mut point = [0.; 3];
+        point[a0] = self.center[a0] + self.radius * libm::cosf(angle);
+        point[a1] = self.center[a1] + self.radius * libm::sinf(angle);
+        point[normal] = self.start[normal] + (self.end[normal] - self.start[normal]) * ratio;
+        point
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLANES: [Plane; 3] = [Plane::XY, Plane::XZ, Plane::YZ];
+
+    /// The point at `a` and `b` on the axes of `plane` and at `normal` on the remaining one.
+    fn point(plane: Plane, a: f32, b: f32, normal: f32) -> [f32; 3] {
+        let (a0, a1, n) = axes(plane);
+        let mut point = [0.; 3];
+        point[a0] = a;
+        point[a1] = b;
+        point[n] = normal;
+        point
+    }
+
+    /// The center offsets `a` and `b` along the axes of `plane`.
+    fn offsets(plane: Plane, a: f32, b: f32) -> [Option<f32>; 3] {
+        let (a0, a1, _) = axes(plane);
+        let mut offsets = [None; 3];
+        offsets[a0] = Some(a);
+        offsets[a1] = Some(b);
+        offsets
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            libm::fabsf(actual - expected) < 1e-4,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    fn assert_points_close(actual: [f32; 3], expected: [f32; 3]) {
+        for (a, e) in actual.iter().zip(&expected) {
+            assert_close(*a, *e);
+        }
+    }
+
+    #[test]
+    fn centers_from_offsets() {
+        use ArcDirection::*;
+
+        for plane in PLANES.iter().copied() {
+            let start = point(plane, 0., 0., 0.);
+            let end = point(plane, 10., 10., 0.);
+            let resolved = center(plane, Clockwise, start, end, offsets(plane, 10., 0.), None);
+            assert_eq!(resolved, Ok(point(plane, 10., 0., 0.)));
+            let arc = Arc::new(plane, Clockwise, start, end, resolved.unwrap());
+            assert_close(arc.radius, 10.);
+            assert_close(arc.sweep, -PI / 2.);
+
+            // Going the other way around.
+            let arc = Arc::new(plane, CounterClockwise, start, end, resolved.unwrap());
+            assert_close(arc.sweep, 3. * PI / 2.);
+
+            // A single offset leaves the other one at 0.
+            let mut single = [None; 3];
+            single[axes(plane).1] = Some(-5.);
+            let end = point(plane, 0., -10., 0.);
+            assert_eq!(
+                center(plane, Clockwise, start, end, single, None),
+                Ok(point(plane, 0., -5., 0.))
+            );
+        }
+    }
+
+    #[test]
+    fn centers_from_radius() {
+        use ArcDirection::*;
+
+        for plane in PLANES.iter().copied() {
+            let start = point(plane, 0., 0., 0.);
+            let end = point(plane, 10., 10., 0.);
+            let cases = [
+                (Clockwise, 10., point(plane, 10., 0., 0.), -PI / 2.),
+                (Clockwise, -10., point(plane, 0., 10., 0.), -3. * PI / 2.),
+                (CounterClockwise, 10., point(plane, 0., 10., 0.), PI / 2.),
+                (
+                    CounterClockwise,
+                    -10.,
+                    point(plane, 10., 0., 0.),
+                    3. * PI / 2.,
+                ),
+            ];
+            for (direction, radius, expected, sweep) in cases.iter().copied() {
+                let resolved = center(plane, direction, start, end, [None; 3], Some(radius));
+                let resolved = resolved.unwrap();
+                assert_points_close(resolved, expected);
+                let arc = Arc::new(plane, direction, start, end, resolved);
+                assert_close(arc.radius, 10.);
+                assert_close(arc.sweep, sweep);
+            }
+
+            // A half turn has its center in the middle, whatever the sign of the radius.
+            let end = point(plane, 20., 0., 0.);
+            for radius in [10., -10.].iter().copied() {
+                let resolved = center(plane, Clockwise, start, end, [None; 3], Some(radius));
+                assert_points_close(resolved.unwrap(), point(plane, 10., 0., 0.));
+            }
+        }
+    }
+
+    #[test]
+    fn chords_stay_within_tolerance() {
+        for (radius, tolerance) in [(50., 0.01), (2., 0.1), (0.005, 0.01)].iter().copied() {
+            let start = [0.; 3];
+            let arc = Arc::new(
+                Plane::XY,
+                ArcDirection::CounterClockwise,
+                start,
+                start,
+                [radius, 0., 0.],
+            );
+            assert_close(arc.sweep, 2. * PI);
+
+            let count = arc.chords(tolerance);
+            let mut previous = arc.point(0, count);
+            assert_points_close(previous, start);
+            for n in 1..=count {
+                let next = arc.point(n, count);
+                for p in [previous, next].iter() {
+                    assert_close(libm::hypotf(p[0] - radius, p[1]), radius);
+                }
+                // Deviation in the middle of the chord.
+                let middle = [(previous[0] + next[0]) / 2., (previous[1] + next[1]) / 2.];
+                let sagitta = radius - libm::hypotf(middle[0] - radius, middle[1]);
+                assert!(sagitta <= tolerance + 1e-4, "{} > {}", sagitta, tolerance);
+                previous = next;
+            }
+
+            if tolerance < radius {
+                // No more chords than needed.
+                let angle = 2. * PI / (count - 1) as f32;
+                assert!(radius * (1. - libm::cosf(angle / 2.)) > tolerance);
+            } else {
+                // A circle smaller than the tolerance still goes round.
+                assert_eq!(count, 2);
+            }
+        }
+    }
+
+    #[test]
+    fn helix_is_linear_on_the_normal_axis() {
+        for plane in PLANES.iter().copied() {
+            let start = point(plane, 0., 0., 0.);
+            let end = point(plane, 0., 0., 8.);
+            let arc = Arc::new(
+                plane,
+                ArcDirection::Clockwise,
+                start,
+                end,
+                point(plane, 0., 5., 0.),
+            );
+            assert_close(arc.sweep, -2. * PI);
+
+            let count = arc.chords(0.01);
+            let normal = axes(plane).2;
+            for n in 0..count {
+                let p = arc.point(n, count);
+                assert_close(p[normal], 8. * n as f32 / count as f32);
+                let (a0, a1, _) = axes(plane);
+                assert_close(libm::hypotf(p[a0], p[a1] - 5.), 5.);
+            }
+            // The end point is hit exactly.
+            assert_eq!(arc.point(count, count), end);
+        }
+    }
+
+    #[test]
+    fn rejects_impossible_arcs() {
+        use ArcDirection::*;
+
+        let plane = Plane::XY;
+        let start = [0.; 3];
+        let end = [20., 0., 0.];
+        let resolve = |offsets, radius| center(plane, Clockwise, start, end, offsets, radius);
+
+        assert_eq!(resolve([None; 3], None), Err(Error::MissingCenter));
+        assert_eq!(
+            resolve(offsets(plane, 10., 0.), Some(10.)),
+            Err(Error::CenterAndRadius)
+        );
+        assert_eq!(
+            resolve(offsets(plane, 0., 0.), None),
+            Err(Error::ZeroRadius)
+        );
+        assert_eq!(
+            resolve(offsets(plane, 9., 0.), None),
+            Err(Error::RadiusMismatch {
+                start: 9.,
+                end: 11.,
+            })
+        );
+        // The rounding of the end point is tolerated.
+        let rounded = [20.004, 0., 0.];
+        let resolved = center(
+            plane,
+            Clockwise,
+            start,
+            rounded,
+            offsets(plane, 10., 0.),
+            None,
+        );
+        assert_eq!(resolved, Ok([10., 0., 0.]));
+
+        for radius in [9.9, -9.9].iter().copied() {
+            assert_eq!(
+                resolve([None; 3], Some(radius)),
+                Err(Error::RadiusTooSmall {
+                    radius: 9.9,
+                    distance: 20.,
+                })
+            );
+        }
+        assert_eq!(
+            center(plane, Clockwise, start, start, [None; 3], Some(5.)),
+            Err(Error::UndefinedCenter)
+        );
+        // Only the height changes, which still leaves the center undefined.
+        assert_eq!(
+            center(plane, Clockwise, start, [0., 0., 5.], [None; 3], Some(5.)),
+            Err(Error::UndefinedCenter)
+        );
+    }
+}
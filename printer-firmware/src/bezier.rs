@@ -0,0 +1,192 @@
+//! Flattening of the G5 cubic Bézier curves.
+//!
+//! A curve is defined in the XY plane by its start and end points and two control points, the
+//! first one given relative to the start point (I and J) and the second one relative to the end
+//! point (P and Q). It is split in halves until each piece is close enough to a straight line to be
+//! run as one, so that tight bends get more lines than gentle ones.
+
+use arrayvec::ArrayVec;
+
+/// Maximum number of halvings, bounding the number of lines to 2^16 per curve.
+const MAX_DEPTH: usize = 16;
+
+type Point = [f32; 2];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Bezier {
+    points: [Point; 4],
+}
+
+fn lerp(a: Point, b: Point) -> Point {
+    [(a[0] + b[0]) / 2., (a[1] + b[1]) / 2.]
+}
+
+impl Bezier {
+    pub fn new(start: Point, end: Point, first: Point, second: Point) -> Self {
+        Self {
+            points: [
+                start,
+                [start[0] + first[0], start[1] + first[1]],
+                [end[0] + second[0], end[1] + second[1]],
+                end,
+            ],
+        }
+    }
+
+    /// Splits the curve at its middle with de Casteljau's algorithm.
+    fn split(&self) -> (Self, Self) {
+        let [p0, p1, p2, p3] = self.points;
+        let (a, b, c) = (lerp(p0, p1), lerp(p1, p2), lerp(p2, p3));
+        let (d, e) = (lerp(a, b), lerp(b, c));
+        let middle = lerp(d, e);
+        (
+            Self {
+                points: [p0, a, d, middle],
+            },
+            Self {
+                points: [middle, e, c, p3],
+            },
+        )
+    }
+
+    /// Whether the curve stays within `tolerance` of its chord.
+    fn is_flat(&self, tolerance: f32) -> bool {
+        let [p0, p1, p2, p3] = self.points;
+        let u = |axis: usize| 3. * p1[axis] - 2. * p0[axis] - p3[axis];
+        let v = |axis: usize| 3. * p2[axis] - p0[axis] - 2. * p3[axis];
+        let sq = |x: f32| x * x;
+        sq(u(0)).max(sq(v(0))) + sq(u(1)).max(sq(v(1))) <= 16. * sq(tolerance)
+    }
+
+    /// End points of the lines approximating the curve within `tolerance`, in order.
+    pub fn flatten(&self, tolerance: f32) -> Flatten {
+        let mut pending = ArrayVec::new();
+        pending.push((*self, 0));
+        Flatten { pending, tolerance }
+    }
+}
+
+pub struct Flatten {
+    /// Pieces of the curve left to flatten, the next one on top, with their depth.
+    pending: ArrayVec<[(Bezier, usize); MAX_DEPTH + 1]>,
+    tolerance: f32,
+}
+
+impl Iterator for Flatten {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        let (mut piece, mut depth) = self.pending.pop()?;
+        while depth < MAX_DEPTH && !piece.is_flat(self.tolerance) {
+            let (first, second) = piece.split();
+            depth += 1;
+            self.pending.push((second, depth));
+            piece = first;
+        }
+        Some(piece.points[3])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve() -> Bezier {
+        Bezier::new([1., 2.], [50., -30.], [20., 40.], [-10., 25.])
+    }
+
+    /// The point of `curve` at `t`.
+    fn at(curve: &Bezier, t: f32) -> Point {
+        let [p0, p1, p2, p3] = curve.points;
+        let s = 1. - t;
+        let weights = [s * s * s, 3. * s * s * t, 3. * s * t * t, t * t * t];
+        let mut point = [0.; 2];
+        for (axis, p) in point.iter_mut().enumerate() {
+            *p = weights[0] * p0[axis]
+                + weights[1] * p1[axis]
+                + weights[2] * p2[axis]
+                + weights[3] * p3[axis];
+        }
+        point
+    }
+
+    /// Distance from `p` to the line going from `a` to `b`.
+    fn distance(p: Point, a: Point, b: Point) -> f32 {
+        let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+        let length = dx * dx + dy * dy;
+        let t = if length == 0. {
+            0.
+        } else {
+            (((p[0] - a[0]) * dx + (p[1] - a[1]) * dy) / length).clamp(0., 1.)
+        };
+        libm::hypotf(p[0] - a[0] - t * dx, p[1] - a[1] - t * dy)
+    }
+
+    #[test]
+    fn hits_the_end_points() {
+        let points: Vec<_> = curve().flatten(0.01).collect();
+        assert!(points.len() > 1);
+        assert_eq!(points.last(), Some(&[50., -30.]));
+        // The start point is where the tool already is.
+        assert_ne!(points[0], [1., 2.]);
+    }
+
+    #[test]
+    fn stays_within_tolerance() {
+        for tolerance in [0.5, 0.01].iter().copied() {
+            let curve = curve();
+            let mut lines = vec![[1., 2.]];
+            lines.extend(curve.flatten(tolerance));
+
+            // Every point of the curve is close to a line and every line close to the curve.
+            let samples: Vec<_> = (0..=2000).map(|n| at(&curve, n as f32 / 2000.)).collect();
+            let to_lines = |p: Point| {
+                lines
+                    .windows(2)
+                    .map(|line| distance(p, line[0], line[1]))
+                    .fold(f32::INFINITY, f32::min)
+            };
+            for sample in &samples {
+                assert!(to_lines(*sample) <= tolerance * 1.01);
+            }
+            for line in lines.windows(2) {
+                for n in 0..=10 {
+                    let ratio = n as f32 / 10.;
+                    let p = [
+                        line[0][0] + (line[1][0] - line[0][0]) * ratio,
+                        line[0][1] + (line[1][1] - line[0][1]) * ratio,
+                    ];
+                    let to_curve = samples
+                        .windows(2)
+                        .map(|s| distance(p, s[0], s[1]))
+                        .fold(f32::INFINITY, f32::min);
+                    assert!(to_curve <= tolerance * 1.01);
+                }
+            }
+        }
+        // The tighter the tolerance, the more lines.
+        assert!(curve().flatten(0.01).count() > curve().flatten(0.5).count());
+    }
+
+    #[test]
+    fn straight_curves_are_one_line() {
+        let straight = Bezier::new([0., 0.], [30., 15.], [10., 5.], [-10., -5.]);
+        assert_eq!(straight.flatten(0.001).collect::<Vec<_>>(), [[30., 15.]]);
+
+        let point = Bezier::new([4., 4.], [4., 4.], [0., 0.], [0., 0.]);
+        assert_eq!(point.flatten(0.001).collect::<Vec<_>>(), [[4., 4.]]);
+    }
+
+    #[test]
+    fn halvings_are_bounded() {
+        // No piece is ever flat against a NaN tolerance, only the depth limit stops the halvings.
+        let mut count = 0;
+        let mut last = None;
+        for point in curve().flatten(f32::NAN) {
+            count += 1;
+            last = Some(point);
+        }
+        assert_eq!(count, 1 << MAX_DEPTH);
+        assert_eq!(last, Some([50., -30.]));
+    }
+}
@@ -0,0 +1,121 @@
+//! Machine configuration.
+//!
+//! Per axis values are given in the `[X, Y, Z, E]` order, which is the order of the motors
+//! (`[A, B, C, E]`) for per motor values when the kinematics are not cartesian.
+
+use crate::kinematics::Kinematics;
+
+/// Number of axes driven by a stepper: X, Y, Z and the extruder.
+pub const AXIS_COUNT: usize = 4;
+
+/// Axis names, as used in the G-code.
+pub const AXIS_NAMES: [char; AXIS_COUNT] = ['X', 'Y', 'Z', 'E'];
+
+/// Mechanics linking the motors to the tool.
+pub const KINEMATICS: Kinematics = Kinematics::Cartesian;
+
+/// Length of the pieces moves are cut into when the kinematics or the bed leveling correction are
+/// not linear, in mm.
+pub const KINEMATIC_SEGMENT_LENGTH: f32 = 1.;
+
+/// Resolution of each motor, in steps per mm.
+pub const STEPS_PER_MM: [f32; AXIS_COUNT] = [80., 80., 400., 93.];
+
+/// Speed limit of each motor, in mm/s.
+pub const MAX_SPEED: [f32; AXIS_COUNT] = [300., 300., 5., 25.];
+
+/// Acceleration limit of each motor, in mm/s².
+pub const MAX_ACCELERATION: [f32; AXIS_COUNT] = [3000., 3000., 100., 10000.];
+
+/// How far from the programmed corner the tool may pass, in mm. The larger it is, the faster
+/// corners are taken.
+pub const JUNCTION_DEVIATION: f32 = 0.05;
+
+/// Largest distance between an arc and the chords it is run as, in mm.
+pub const ARC_TOLERANCE: f32 = 0.01;
+
+/// Largest distance between a Bézier curve and the lines it is run as, in mm.
+pub const BEZIER_TOLERANCE: f32 = 0.01;
+
+/// Level of each axis' endstop pin when it is triggered, `true` for high. Endstops are at the
+/// minimum of the X, Y and Z axes, which is where they are homed to: position 0.
+pub const ENDSTOP_TRIGGERED_HIGH: [bool; 3] = [true, true, true];
+
+/// Travel limits of the X, Y and Z axes, in mm from their endstop. Moves past them are handled
+/// according to [`SOFT_LIMIT_POLICY`] once the axes are homed, unless disabled by M211.
+pub const TRAVEL_MIN: [f32; 3] = [0., 0., 0.];
+pub const TRAVEL_MAX: [f32; 3] = [200., 200., 180.];
+
+/// What to do with the moves going past the travel limits.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoftLimitPolicy {
+    /// Refuse them.
+    Reject,
+    /// Stop straight moves at the limits. Curves and drilling cycles are still refused as they
+    /// would be distorted.
+    Clip,
+}
+
+pub const SOFT_LIMIT_POLICY: SoftLimitPolicy = SoftLimitPolicy::Reject;
+
+/// Whether absolute moves are refused on the axes that were not homed.
+pub const HOMING_REQUIRED: bool = true;
+
+/// Order the axes are homed in, one after the other.
+pub const HOMING_ORDER: [usize; 3] = [2, 0, 1];
+
+/// Longest distance travelled looking for each endstop, in mm.
+pub const HOMING_TRAVEL: [f32; 3] = [250., 250., 200.];
+
+/// Speed each endstop is first looked for at, in mm/s. There is no acceleration.
+pub const HOMING_FAST_SPEED: [f32; 3] = [30., 30., 4.];
+
+/// Speed each endstop is located precisely at, after backing off from it, in mm/s.
+pub const HOMING_SLOW_SPEED: [f32; 3] = [3., 3., 1.];
+
+/// Distance moved away from each endstop once found, in mm.
+pub const HOMING_BACK_OFF: [f32; 3] = [5., 5., 2.];
+
+/// Level of the probe pin when it is triggered, `true` for high. The probe is the nozzle itself
+/// (eg. a load cell or a piezo sensor), it has no offset.
+pub const PROBE_TRIGGERED_HIGH: bool = false;
+
+/// Height the probe is raised to between two points, in mm.
+pub const PROBE_CLEARANCE: f32 = 5.;
+
+/// Longest distance travelled down looking for the bed, in mm.
+pub const PROBE_TRAVEL: f32 = 10.;
+
+/// Speed the bed is looked for at, in mm/s.
+pub const PROBE_SPEED: f32 = 2.;
+
+/// Number of points of the bed leveling mesh along X and Y, at least 2 each.
+pub const MESH_POINTS_X: usize = 4;
+pub const MESH_POINTS_Y: usize = 4;
+
+/// Corners of the area covered by the bed leveling mesh, in mm.
+pub const MESH_MIN: [f32; 2] = [10., 10.];
+pub const MESH_MAX: [f32; 2] = [190., 190.];
+
+/// How the height of the bed is interpolated between the points of the mesh.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Interpolation {
+    Bilinear,
+    /// Smoother, through Catmull-Rom splines, at the cost of cutting moves in short pieces.
+    Bicubic,
+}
+
+pub const MESH_INTERPOLATION: Interpolation = Interpolation::Bilinear;
+
+/// Shape of the speed changes.
+#[allow(dead_code)]
+pub enum RampShape {
+    /// Constant acceleration.
+    Trapezoidal,
+    /// The acceleration changes progressively, at `jerk` mm/s³, which reduces ringing.
+    SCurve { jerk: f32 },
+}
+
+pub const RAMP_SHAPE: RampShape = RampShape::SCurve { jerk: 100_000. };
@@ -1,35 +1,217 @@
+//! A minimal executor running a fixed set of tasks.
+//!
+//! Each task owns a ready flag that its waker raises. Only the tasks that were woken are polled
+//! again and the core is parked when none of them is ready.
+
 use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 
-use pin_utils::pin_mut;
-
 const VTABLE: RawWakerVTable = {
     unsafe fn clone(s: *const ()) -> RawWaker {
         RawWaker::new(s, &VTABLE)
     }
-    unsafe fn wake(_: *const ()) {}
-    unsafe fn wake_by_ref(_: *const ()) {}
+    unsafe fn wake(s: *const ()) {
+        (*(s as *const AtomicBool)).store(true, Ordering::Release);
+    }
     unsafe fn drop(_: *const ()) {}
 
-    RawWakerVTable::new(clone, wake, wake_by_ref, drop)
+    RawWakerVTable::new(clone, wake, wake, drop)
 };
 
-pub fn block_on<T>(t: T) -> T::Output
-where
-    T: Future,
-{
-    let raw_waker = RawWaker::new(core::ptr::null(), &VTABLE);
-    pin_mut!(t);
-
-    unsafe {
-        let waker = Waker::from_raw(raw_waker);
-        let mut ctx = Context::from_waker(&waker);
-
-        loop {
-            match t.as_mut().poll(&mut ctx) {
-                Poll::Ready(out) => return out,
-                Poll::Pending => {}
+pub struct Task<'a> {
+    ready: AtomicBool,
+    done: bool,
+    future: Pin<&'a mut dyn Future<Output = ()>>,
+}
+
+impl<'a> Task<'a> {
+    /// Creates a task, ready to be polled for the first time.
+    pub fn new(future: Pin<&'a mut dyn Future<Output = ()>>) -> Self {
+        Self {
+            ready: AtomicBool::new(true),
+            done: false,
+            future,
+        }
+    }
+}
+
+/// Runs tasks until they complete.
+///
+/// The wakers handed to the tasks point into the task slice so it must outlive any waker that
+/// may be stored (eg. by an interrupt handler). On target, the tasks never complete so
+/// [`Executor::run`] never returns which trivially guarantees it.
+pub struct Executor<'a, 'b> {
+    tasks: &'a mut [Task<'b>],
+}
+
+impl<'a, 'b> Executor<'a, 'b> {
+    pub fn new(tasks: &'a mut [Task<'b>]) -> Self {
+        Self { tasks }
+    }
+
+    /// Polls every task that was woken since it was last polled.
+    ///
+    /// Returns `false` if no task was ready.
+    pub fn poll(&mut self) -> bool {
+        let mut polled = false;
+        for task in self.tasks.iter_mut().filter(|task| !task.done) {
+            if !task.ready.swap(false, Ordering::Acquire) {
+                continue;
+            }
+            polled = true;
+
+            let raw_waker = RawWaker::new(&task.ready as *const AtomicBool as *const (), &VTABLE);
+            // SAFETY: the vtable only ever stores to the AtomicBool the data pointer points to.
+            let waker = unsafe { Waker::from_raw(raw_waker) };
+            let mut ctx = Context::from_waker(&waker);
+            if let Poll::Ready(()) = task.future.as_mut().poll(&mut ctx) {
+                task.done = true;
+            }
+        }
+        polled
+    }
+
+    /// Whether a task is waiting to be polled.
+    pub fn is_ready(&self) -> bool {
+        self.tasks
+            .iter()
+            .any(|task| !task.done && task.ready.load(Ordering::Acquire))
+    }
+
+    /// Whether all the tasks have completed.
+    pub fn is_done(&self) -> bool {
+        self.tasks.iter().all(|task| task.done)
+    }
+
+    /// Polls the tasks until they all complete. Whenever none of them is ready, `idle` is called
+    /// and the core is parked unless it woke one of them.
+    pub fn run(&mut self, mut idle: impl FnMut()) {
+        while !self.is_done() {
+            if !self.poll() {
+                idle();
+                self.park();
             }
         }
     }
+
+    #[cfg(not(feature = "platform-sim"))]
+    fn park(&self) {
+        // A wake-up may come from an interrupt between the check and the WFI. With interrupts
+        // masked a pending interrupt still ends the WFI and is serviced right after.
+        cortex_m::interrupt::free(|_| {
+            if !self.is_ready() {
+                cortex_m::asm::wfi();
+            }
+        });
+    }
+
+    #[cfg(feature = "platform-sim")]
+    fn park(&self) {
+        // Wakers don't know about the thread running the executor, bound the wake-up latency.
+        if !self.is_ready() {
+            std::thread::park_timeout(std::time::Duration::from_millis(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use futures::future;
+    use pin_utils::pin_mut;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    /// A task counting its polls and keeping its waker, done once `done` is set.
+    fn task<'a>(
+        polls: &'a Cell<u32>,
+        waker: &'a Mutex<Option<Waker>>,
+        done: &'a Cell<bool>,
+    ) -> impl Future<Output = ()> + 'a {
+        future::poll_fn(move |cx| {
+            polls.set(polls.get() + 1);
+            *waker.lock().unwrap() = Some(cx.waker().clone());
+            if done.get() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+    }
+
+    #[test]
+    fn only_polls_woken_tasks() {
+        let (polls_a, polls_b) = (Cell::new(0), Cell::new(0));
+        let (waker_a, waker_b) = (Mutex::new(None), Mutex::new(None));
+        let (done_a, done_b) = (Cell::new(false), Cell::new(false));
+        let a = task(&polls_a, &waker_a, &done_a);
+        let b = task(&polls_b, &waker_b, &done_b);
+        pin_mut!(a);
+        pin_mut!(b);
+        let mut tasks = [Task::new(a), Task::new(b)];
+        let mut executor = Executor::new(&mut tasks);
+
+        // Every task is polled once to start with.
+        assert!(executor.is_ready());
+        assert!(executor.poll());
+        assert_eq!((polls_a.get(), polls_b.get()), (1, 1));
+        assert!(!executor.is_ready());
+        assert!(!executor.poll());
+
+        waker_b.lock().unwrap().take().unwrap().wake();
+        assert!(executor.is_ready());
+        assert!(executor.poll());
+        assert_eq!((polls_a.get(), polls_b.get()), (1, 2));
+
+        // Completed tasks are never polled again.
+        done_b.set(true);
+        waker_b.lock().unwrap().take().unwrap().wake();
+        assert!(executor.poll());
+        assert!(!executor.is_done());
+        waker_b.lock().unwrap().take().unwrap().wake();
+        assert!(!executor.poll());
+        assert_eq!(polls_b.get(), 3);
+
+        done_a.set(true);
+        waker_a.lock().unwrap().take().unwrap().wake_by_ref();
+        assert!(executor.poll());
+        assert!(executor.is_done());
+    }
+
+    #[test]
+    fn parks_until_woken_from_another_thread() {
+        let polls = Cell::new(0);
+        let waker = Arc::new(Mutex::new(None::<Waker>));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let (task_waker, task_done) = (Arc::clone(&waker), Arc::clone(&done));
+        let fut = future::poll_fn(|cx| {
+            polls.set(polls.get() + 1);
+            *task_waker.lock().unwrap() = Some(cx.waker().clone());
+            if task_done.load(Ordering::Acquire) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+        pin_mut!(fut);
+        let mut tasks = [Task::new(fut)];
+
+        // Stands for an interrupt handler.
+        let interrupt = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            done.store(true, Ordering::Release);
+            if let Some(waker) = waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        });
+        Executor::new(&mut tasks).run(|| {});
+        interrupt.join().unwrap();
+        // Parking doesn't poll the task, only the wake-up does.
+        assert_eq!(polls.get(), 2);
+    }
 }
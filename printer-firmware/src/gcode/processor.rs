@@ -1,9 +1,27 @@
-enum MoveType {
+//! Conversion of a line of G-code words into typed commands.
+
+use async_gcode::{GCode, Literal, RealValue};
+
+use crate::state::{MotionMode, Plane, Positioning, RetractMode, Unit};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MoveType {
+    /// G0: as fast as possible
     Quick,
+    /// G1: at the programmed feed rate
+    Linear,
 }
 
-enum Command {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArcDirection {
+    Clockwise,
+    CounterClockwise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
     LinearMove {
+        move_type: MoveType,
         /// X axis
         x: Option<f32>,
         /// Y axis
@@ -13,23 +31,824 @@ enum Command {
         /// Extruder axis
         e: Option<f32>,
     },
+    ArcMove {
+        direction: ArcDirection,
+        /// End point
+        x: Option<f32>,
+        y: Option<f32>,
+        z: Option<f32>,
+        e: Option<f32>,
+        /// Center offsets from the start point
+        i: Option<f32>,
+        j: Option<f32>,
+        k: Option<f32>,
+        /// Radius
+        r: Option<f32>,
+    },
+    /// Cubic Bézier curve in the XY plane
+    BezierMove {
+        /// End point
+        x: Option<f32>,
+        y: Option<f32>,
+        e: Option<f32>,
+        /// First control point, relative to the start point
+        i: Option<f32>,
+        j: Option<f32>,
+        /// Second control point, relative to the end point
+        p: Option<f32>,
+        q: Option<f32>,
+    },
+    /// Canned drilling cycle: moves to the hole in the plane, then drills along the normal axis
+    /// from the R level down to the level given by the normal axis' word
+    Drill {
+        x: Option<f32>,
+        y: Option<f32>,
+        z: Option<f32>,
+        /// Level the drilling starts from
+        r: Option<f32>,
+        /// Level the tool goes back to, resolved by the state
+        retract: Option<f32>,
+    },
     /// Maximum movement speed
     FeedRate(f32),
+    /// Pause for a duration in seconds
+    Dwell(f32),
+    /// Home the selected axes
+    Home {
+        x: bool,
+        y: bool,
+        z: bool,
+    },
+    /// Probe the bed on the mesh grid and enable the leveling correction
+    ProbeMesh,
+    /// Enable or disable the leveling correction if given, then report the mesh if asked to
+    SetLeveling {
+        enabled: Option<bool>,
+        report: bool,
+    },
+    /// Overwrite the current position without moving
+    SetPosition {
+        x: Option<f32>,
+        y: Option<f32>,
+        z: Option<f32>,
+        e: Option<f32>,
+    },
+    CancelMotionMode,
+    SetPlane(Plane),
+    SetUnit(Unit),
+    SetPositioning(Positioning),
+    /// Positioning of the extruder, independent from the other axes'
+    SetExtruderPositioning(Positioning),
+    SetRetractMode(RetractMode),
+    /// Select a work coordinate system from 0 (G54) to 8 (G59.3)
+    SelectWorkspace(u8),
+    /// Set the offsets of a work coordinate system, the active one if `None`
+    SetWorkspaceOffset {
+        workspace: Option<u8>,
+        x: Option<f32>,
+        y: Option<f32>,
+        z: Option<f32>,
+    },
+    /// Set the offsets of a work coordinate system, the active one if `None`, so that the current
+    /// position has the given coordinates in it
+    SetWorkspacePosition {
+        workspace: Option<u8>,
+        x: Option<f32>,
+        y: Option<f32>,
+        z: Option<f32>,
+    },
+    /// Cancel the offsets set by G92
+    ResetPositionOffset,
+    /// Report the offsets of a work coordinate system, the active one if `None`, and the G92 ones
+    ReportWorkspace(Option<u8>),
+    /// Enable or disable the travel limits if given, then report them
+    SetSoftLimits(Option<bool>),
+    EnableSteppers,
+    DisableSteppers,
+    SetHotendTemperature {
+        target: f32,
+        wait: bool,
+    },
+    SetBedTemperature {
+        target: f32,
+        wait: bool,
+    },
+    /// Fan duty cycle from 0.0 to 1.0
+    SetFanSpeed(f32),
+    ReportTemperatures,
+    ReportPosition,
+    ReportFirmware,
+    /// Number of the current line, the next one is expected to be numbered `n + 1`
+    SetLineNumber(u32),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The G or M code is not supported.
+    UnknownCommand(char, f32),
+    /// The G or M code is negative or has more than one decimal.
+    MalformedCommand(char, f32),
+    /// A command requires a parameter that is missing from the line.
+    MissingParameter(char),
+    /// A word was given without a value where one is required.
+    MissingValue(char),
+    /// The value is out of the range accepted by the command.
+    InvalidValue(char, f32),
+    /// Axis words were given without any active motion mode.
+    NoMotionMode,
+    /// A parameter was given to a command that doesn't accept it.
+    UnexpectedParameter(char),
+}
+
+fn value(v: &RealValue) -> Option<f32> {
+    match v {
+        RealValue::Literal(Literal::RealNumber(n)) => Some(*n as f32),
+        _ => None,
+    }
+}
+
+/// Splits a command number such as `59.1` in its major and minor parts, if it is positive with at
+/// most one decimal.
+fn code(v: f32) -> Option<(u32, u32)> {
+    let tenths = v * 10.;
+    let rounded = libm::roundf(tenths);
+    // Leaves room for the rounding of the decimal value to the nearest `f32`.
+    if rounded >= 0. && libm::fabsf(tenths - rounded) <= 1e-5 * rounded.max(1.) {
+        let tenths = rounded as u32;
+        Some((tenths / 10, tenths % 10))
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct Processor {}
 impl Processor {
-    pub fn process(&mut self) -> Iter {
-        Iter { proc: self }
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    /// Iterates over the commands found on `line`.
+    ///
+    /// `motion_mode` is the modal motion mode the line is interpreted in when it only carries axis
+    /// words.
+    pub fn process<'a>(&self, line: &'a [GCode], motion_mode: MotionMode) -> Iter<'a> {
+        Iter {
+            line,
+            motion_mode,
+            feedrate_done: false,
+            position: 0,
+            axes_used: false,
+            done: false,
+        }
     }
 }
 
 pub struct Iter<'a> {
-    proc: &'a mut Processor,
+    line: &'a [GCode],
+    motion_mode: MotionMode,
+    feedrate_done: bool,
+    /// index of the next word to look at
+    position: usize,
+    /// whether the axis words have been consumed by a command
+    axes_used: bool,
+    done: bool,
+}
+
+impl Iter<'_> {
+    /// Looks for `letter` on the line.
+    ///
+    /// Returns `None` if absent, `Some(None)` if present without a value.
+    fn word(&self, letter: char) -> Option<Option<f32>> {
+        self.line.iter().find_map(|word| match word {
+            GCode::Word(l, v) if *l == letter => Some(value(v)),
+            _ => None,
+        })
+    }
+
+    /// Value of an optional parameter.
+    fn optional(&self, letter: char) -> Result<Option<f32>, Error> {
+        match self.word(letter) {
+            Some(v) => v.map(Some).ok_or(Error::MissingValue(letter)),
+            None => Ok(None),
+        }
+    }
+
+    /// Value of a mandatory parameter.
+    fn required(&self, letter: char) -> Result<f32, Error> {
+        self.optional(letter)?
+            .ok_or(Error::MissingParameter(letter))
+    }
+
+    fn has_axes(&self) -> bool {
+        ['x', 'y', 'z', 'e']
+            .iter()
+            .any(|&axis| self.word(axis).is_some())
+    }
+
+    fn motion(&mut self, mode: MotionMode) -> Result<Command, Error> {
+        self.axes_used = true;
+        let (x, y, z, e) = (
+            self.optional('x')?,
+            self.optional('y')?,
+            self.optional('z')?,
+            self.optional('e')?,
+        );
+        Ok(match mode {
+            MotionMode::RapidLinear | MotionMode::Linear => Command::LinearMove {
+                move_type: if mode == MotionMode::RapidLinear {
+                    MoveType::Quick
+                } else {
+                    MoveType::Linear
+                },
+                x,
+                y,
+                z,
+                e,
+            },
+            MotionMode::ClockwiseControledArc | MotionMode::CounterClockwiseControledArc => {
+                Command::ArcMove {
+                    direction: if mode == MotionMode::ClockwiseControledArc {
+                        ArcDirection::Clockwise
+                    } else {
+                        ArcDirection::CounterClockwise
+                    },
+                    x,
+                    y,
+                    z,
+                    e,
+                    i: self.optional('i')?,
+                    j: self.optional('j')?,
+                    k: self.optional('k')?,
+                    r: self.optional('r')?,
+                }
+            }
+            MotionMode::BezierCubicSpline => {
+                if z.is_some() {
+                    return Err(Error::UnexpectedParameter('z'));
+                }
+                let (i, j, p, q) = (
+                    self.optional('i')?,
+                    self.optional('j')?,
+                    self.optional('p')?,
+                    self.optional('q')?,
+                );
+                if i.is_none() && j.is_none() {
+                    return Err(Error::MissingParameter('i'));
+                }
+                if p.is_none() && q.is_none() {
+                    return Err(Error::MissingParameter('p'));
+                }
+                Command::BezierMove {
+                    x,
+                    y,
+                    e,
+                    i,
+                    j,
+                    p,
+                    q,
+                }
+            }
+            MotionMode::Drilling => Command::Drill {
+                x,
+                y,
+                z,
+                r: self.optional('r')?,
+                retract: None,
+            },
+            MotionMode::None => return Err(Error::NoMotionMode),
+        })
+    }
+
+    fn g(&mut self, v: f32) -> Result<Command, Error> {
+        Ok(match code(v).ok_or(Error::MalformedCommand('g', v))? {
+            (0, 0) => return self.motion(MotionMode::RapidLinear),
+            (1, 0) => return self.motion(MotionMode::Linear),
+            (2, 0) => return self.motion(MotionMode::ClockwiseControledArc),
+            (3, 0) => return self.motion(MotionMode::CounterClockwiseControledArc),
+            (5, 0) => return self.motion(MotionMode::BezierCubicSpline),
+            (4, 0) => match (self.optional('p')?, self.optional('s')?) {
+                (Some(ms), _) if ms < 0. => return Err(Error::InvalidValue('p', ms)),
+                (Some(ms), _) => Command::Dwell(ms / 1000.),
+                (None, Some(s)) if s < 0. => return Err(Error::InvalidValue('s', s)),
+                (None, Some(s)) => Command::Dwell(s),
+                (None, None) => return Err(Error::MissingParameter('p')),
+            },
+            (17, 0) => Command::SetPlane(Plane::XY),
+            (18, 0) => Command::SetPlane(Plane::XZ),
+            (19, 0) => Command::SetPlane(Plane::YZ),
+            (20, 0) => Command::SetUnit(Unit::Inch),
+            (21, 0) => Command::SetUnit(Unit::Millimeter),
+            (28, 0) => {
+                self.axes_used = true;
+                let (x, y, z) = (
+                    self.word('x').is_some(),
+                    self.word('y').is_some(),
+                    self.word('z').is_some(),
+                );
+                let all = !(x || y || z);
+                Command::Home {
+                    x: all || x,
+                    y: all || y,
+                    z: all || z,
+                }
+            }
+            (29, 0) => Command::ProbeMesh,
+            (10, 0) => {
+                self.axes_used = true;
+                let l = self.required('l')?;
+                let p = self.required('p')?;
+                let workspace = match code(p) {
+                    Some((0, 0)) => None,
+                    Some((p @ 1..=9, 0)) => Some((p - 1) as u8),
+                    _ => return Err(Error::InvalidValue('p', p)),
+                };
+                let (x, y, z) = (
+                    self.optional('x')?,
+                    self.optional('y')?,
+                    self.optional('z')?,
+                );
+                match code(l) {
+                    Some((2, 0)) | Some((20, 0)) if x.is_none() && y.is_none() && z.is_none() => {
+                        Command::ReportWorkspace(workspace)
+                    }
+                    Some((2, 0)) => Command::SetWorkspaceOffset { workspace, x, y, z },
+                    Some((20, 0)) => Command::SetWorkspacePosition { workspace, x, y, z },
+                    _ => return Err(Error::InvalidValue('l', l)),
+                }
+            }
+            (major @ 54..=58, 0) => Command::SelectWorkspace((major - 54) as u8),
+            (59, minor @ 0..=3) => Command::SelectWorkspace((5 + minor) as u8),
+            (80, 0) => Command::CancelMotionMode,
+            (81, 0) => return self.motion(MotionMode::Drilling),
+            (90, 0) => Command::SetPositioning(Positioning::Absolute),
+            (91, 0) => Command::SetPositioning(Positioning::Relative),
+            (92, 0) => {
+                self.axes_used = true;
+                Command::SetPosition {
+                    x: self.optional('x')?,
+                    y: self.optional('y')?,
+                    z: self.optional('z')?,
+                    e: self.optional('e')?,
+                }
+            }
+            (92, 1) => Command::ResetPositionOffset,
+            (98, 0) => Command::SetRetractMode(RetractMode::Initial),
+            (99, 0) => Command::SetRetractMode(RetractMode::R),
+            _ => return Err(Error::UnknownCommand('g', v)),
+        })
+    }
+
+    fn m(&mut self, v: f32) -> Result<Command, Error> {
+        Ok(match code(v).ok_or(Error::MalformedCommand('m', v))? {
+            (17, 0) => Command::EnableSteppers,
+            (18, 0) | (84, 0) => Command::DisableSteppers,
+            (82, 0) => Command::SetExtruderPositioning(Positioning::Absolute),
+            (83, 0) => Command::SetExtruderPositioning(Positioning::Relative),
+            (major @ 104, 0) | (major @ 109, 0) => Command::SetHotendTemperature {
+                target: self.required('s')?,
+                wait: major == 109,
+            },
+            (major @ 140, 0) | (major @ 190, 0) => Command::SetBedTemperature {
+                target: self.required('s')?,
+                wait: major == 190,
+            },
+            (105, 0) => Command::ReportTemperatures,
+            (106, 0) => {
+                // S is optional and defaults to full speed.
+                let pwm = self.optional('s')?.unwrap_or(255.);
+                if !(0. ..=255.).contains(&pwm) {
+                    return Err(Error::InvalidValue('s', pwm));
+                }
+                Command::SetFanSpeed(pwm / 255.)
+            }
+            (107, 0) => Command::SetFanSpeed(0.),
+            (114, 0) => Command::ReportPosition,
+            (115, 0) => Command::ReportFirmware,
+            (211, 0) => Command::SetSoftLimits(self.optional('s')?.map(|s| s != 0.)),
+            (420, 0) => Command::SetLeveling {
+                enabled: self.optional('s')?.map(|s| s != 0.),
+                report: self.word('v').is_some(),
+            },
+            (110, 0) => {
+                // Either `N<n> M110` or `M110 N<n>`
+                let n = self
+                    .line
+                    .iter()
+                    .find_map(|word| match word {
+                        GCode::LineNumber(n) => Some(Ok(*n)),
+                        GCode::Word('n', v) => {
+                            Some(value(v).map(|n| n as u32).ok_or(Error::MissingValue('n')))
+                        }
+                        _ => None,
+                    })
+                    .unwrap_or(Err(Error::MissingParameter('n')))?;
+                Command::SetLineNumber(n)
+            }
+            _ => return Err(Error::UnknownCommand('m', v)),
+        })
+    }
 }
+
 impl Iterator for Iter<'_> {
-    type Item = ();
+    type Item = Result<Command, Error>;
     fn next(&mut self) -> Option<Self::Item> {
-        None
+        if self.done {
+            return None;
+        }
+
+        // The feed rate applies to the motion on the same line so it is reported first.
+        if !self.feedrate_done {
+            self.feedrate_done = true;
+            match self.optional('f') {
+                Ok(Some(f)) => return Some(Ok(Command::FeedRate(f))),
+                Ok(None) => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        while let Some(word) = self.line.get(self.position) {
+            self.position += 1;
+            let res = match word {
+                GCode::Word('g', v) => value(v)
+                    .ok_or(Error::MissingValue('g'))
+                    .and_then(|v| self.g(v)),
+                GCode::Word('m', v) => value(v)
+                    .ok_or(Error::MissingValue('m'))
+                    .and_then(|v| self.m(v)),
+                _ => continue,
+            };
+            if res.is_err() {
+                self.done = true;
+            }
+            return Some(res);
+        }
+
+        // Axis words that weren't claimed by any command are a move in the modal motion mode.
+        self.done = true;
+        if !self.axes_used && self.has_axes() {
+            Some(self.motion(self.motion_mode))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, FutureExt};
+
+    /// The words of `line` as the parser gives them, up to its end.
+    fn words(line: &str) -> Vec<GCode> {
+        let bytes = format!("{}\n", line).into_bytes();
+        let input = stream::iter(bytes.into_iter().map(Ok::<_, async_gcode::Error>));
+        let mut parser = async_gcode::Parser::new(input);
+        let mut words = Vec::new();
+        while let Some(Some(word)) = parser.next().now_or_never() {
+            match word.unwrap_or_else(|e| panic!("{:?}: {:?}", line, e)) {
+                GCode::Execute => break,
+                word => words.push(word),
+            }
+        }
+        words
+    }
+
+    fn process(line: &str, motion_mode: MotionMode) -> Vec<Result<Command, Error>> {
+        Processor::new()
+            .process(&words(line), motion_mode)
+            .collect()
+    }
+
+    /// Checks that each line gives the matching commands, outside of any motion mode.
+    fn assert_commands(cases: &[(&str, &[Command])]) {
+        for (line, commands) in cases {
+            let expected: Vec<_> = commands.iter().copied().map(Ok).collect();
+            assert_eq!(process(line, MotionMode::None), expected, "{}", line);
+        }
+    }
+
+    fn assert_error(line: &str, error: Error) {
+        assert_eq!(process(line, MotionMode::None), [Err(error)], "{}", line);
+    }
+
+    #[test]
+    fn moves() {
+        assert_commands(&[
+            (
+                "G0 X1 Z-2",
+                &[Command::LinearMove {
+                    move_type: MoveType::Quick,
+                    x: Some(1.),
+                    y: None,
+                    z: Some(-2.),
+                    e: None,
+                }],
+            ),
+            (
+                // The feed rate comes first, wherever it is on the line.
+                "G1 Y2 E0.5 F1200",
+                &[
+                    Command::FeedRate(1200.),
+                    Command::LinearMove {
+                        move_type: MoveType::Linear,
+                        x: None,
+                        y: Some(2.),
+                        z: None,
+                        e: Some(0.5),
+                    },
+                ],
+            ),
+            ("F300", &[Command::FeedRate(300.)]),
+        ]);
+        // Axis words alone move in the modal motion mode.
+        assert_eq!(
+            process("X3", MotionMode::Linear),
+            [Ok(Command::LinearMove {
+                move_type: MoveType::Linear,
+                x: Some(3.),
+                y: None,
+                z: None,
+                e: None,
+            })]
+        );
+        assert_error("X3", Error::NoMotionMode);
+        assert_error("G1 X", Error::MissingValue('x'));
+    }
+
+    #[test]
+    fn arcs() {
+        assert_commands(&[
+            (
+                "G2 X10 Y0 I5 J0",
+                &[Command::ArcMove {
+                    direction: ArcDirection::Clockwise,
+                    x: Some(10.),
+                    y: Some(0.),
+                    z: None,
+                    e: None,
+                    i: Some(5.),
+                    j: Some(0.),
+                    k: None,
+                    r: None,
+                }],
+            ),
+            (
+                "G3 X10 Z5 R-7",
+                &[Command::ArcMove {
+                    direction: ArcDirection::CounterClockwise,
+                    x: Some(10.),
+                    y: None,
+                    z: Some(5.),
+                    e: None,
+                    i: None,
+                    j: None,
+                    k: None,
+                    r: Some(-7.),
+                }],
+            ),
+        ]);
+    }
+
+    #[test]
+    fn bezier_curves() {
+        assert_commands(&[(
+            "G5 X10 Y5 I2 J0 P-2 Q1 E1",
+            &[Command::BezierMove {
+                x: Some(10.),
+                y: Some(5.),
+                e: Some(1.),
+                i: Some(2.),
+                j: Some(0.),
+                p: Some(-2.),
+                q: Some(1.),
+            }],
+        )]);
+        assert_error("G5 X10 P1 Q1", Error::MissingParameter('i'));
+        assert_error("G5 X10 I1", Error::MissingParameter('p'));
+        assert_error("G5 X10 Z1 I1 P1", Error::UnexpectedParameter('z'));
+    }
+
+    #[test]
+    fn dwell() {
+        assert_commands(&[
+            ("G4 P500", &[Command::Dwell(0.5)]),
+            ("G4 S2", &[Command::Dwell(2.)]),
+        ]);
+        assert_error("G4", Error::MissingParameter('p'));
+        assert_error("G4 P-1", Error::InvalidValue('p', -1.));
+        assert_error("G4 S-1", Error::InvalidValue('s', -1.));
+    }
+
+    #[test]
+    fn homing_and_leveling() {
+        assert_commands(&[
+            (
+                "G28",
+                &[Command::Home {
+                    x: true,
+                    y: true,
+                    z: true,
+                }],
+            ),
+            (
+                "G28 X Z0",
+                &[Command::Home {
+                    x: true,
+                    y: false,
+                    z: true,
+                }],
+            ),
+            ("G29", &[Command::ProbeMesh]),
+            (
+                "M420 S1 V",
+                &[Command::SetLeveling {
+                    enabled: Some(true),
+                    report: true,
+                }],
+            ),
+            (
+                "M420",
+                &[Command::SetLeveling {
+                    enabled: None,
+                    report: false,
+                }],
+            ),
+        ]);
+    }
+
+    #[test]
+    fn positions_and_workspaces() {
+        assert_commands(&[
+            (
+                "G92 X0 E5",
+                &[Command::SetPosition {
+                    x: Some(0.),
+                    y: None,
+                    z: None,
+                    e: Some(5.),
+                }],
+            ),
+            ("G92.1", &[Command::ResetPositionOffset]),
+            ("G54", &[Command::SelectWorkspace(0)]),
+            ("G58", &[Command::SelectWorkspace(4)]),
+            ("G59", &[Command::SelectWorkspace(5)]),
+            ("G59.3", &[Command::SelectWorkspace(8)]),
+            (
+                "G10 L2 P2 X5",
+                &[Command::SetWorkspaceOffset {
+                    workspace: Some(1),
+                    x: Some(5.),
+                    y: None,
+                    z: None,
+                }],
+            ),
+            (
+                "G10 L20 P0 Y1 Z2",
+                &[Command::SetWorkspacePosition {
+                    workspace: None,
+                    x: None,
+                    y: Some(1.),
+                    z: Some(2.),
+                }],
+            ),
+            ("G10 L2 P9", &[Command::ReportWorkspace(Some(8))]),
+        ]);
+        assert_error("G10 P1 X1", Error::MissingParameter('l'));
+        assert_error("G10 L3 P1 X1", Error::InvalidValue('l', 3.));
+        assert_error("G10 L2 P10 X1", Error::InvalidValue('p', 10.));
+        assert_error("G10 L2 P-1 X1", Error::InvalidValue('p', -1.));
+        assert_error("G10 L2 P1.5 X1", Error::InvalidValue('p', 1.5));
+    }
+
+    #[test]
+    fn modes() {
+        assert_commands(&[
+            ("G17", &[Command::SetPlane(Plane::XY)]),
+            ("G18", &[Command::SetPlane(Plane::XZ)]),
+            ("G19", &[Command::SetPlane(Plane::YZ)]),
+            ("G20", &[Command::SetUnit(Unit::Inch)]),
+            ("G21", &[Command::SetUnit(Unit::Millimeter)]),
+            ("G90", &[Command::SetPositioning(Positioning::Absolute)]),
+            ("G91", &[Command::SetPositioning(Positioning::Relative)]),
+            (
+                "M82",
+                &[Command::SetExtruderPositioning(Positioning::Absolute)],
+            ),
+            (
+                "M83",
+                &[Command::SetExtruderPositioning(Positioning::Relative)],
+            ),
+            ("G80", &[Command::CancelMotionMode]),
+            ("G98", &[Command::SetRetractMode(RetractMode::Initial)]),
+            ("G99", &[Command::SetRetractMode(RetractMode::R)]),
+            ("M211 S0", &[Command::SetSoftLimits(Some(false))]),
+            ("M211", &[Command::SetSoftLimits(None)]),
+            // Several commands on a line, in order.
+            (
+                "G91 G20",
+                &[
+                    Command::SetPositioning(Positioning::Relative),
+                    Command::SetUnit(Unit::Inch),
+                ],
+            ),
+        ]);
+    }
+
+    #[test]
+    fn drilling() {
+        assert_commands(&[(
+            "G81 X1 Y2 Z-3 R1",
+            &[Command::Drill {
+                x: Some(1.),
+                y: Some(2.),
+                z: Some(-3.),
+                r: Some(1.),
+                retract: None,
+            }],
+        )]);
+    }
+
+    #[test]
+    fn machine() {
+        assert_commands(&[
+            ("M17", &[Command::EnableSteppers]),
+            ("M18", &[Command::DisableSteppers]),
+            ("M84", &[Command::DisableSteppers]),
+            (
+                "M104 S200",
+                &[Command::SetHotendTemperature {
+                    target: 200.,
+                    wait: false,
+                }],
+            ),
+            (
+                "M109 S210",
+                &[Command::SetHotendTemperature {
+                    target: 210.,
+                    wait: true,
+                }],
+            ),
+            (
+                "M140 S60",
+                &[Command::SetBedTemperature {
+                    target: 60.,
+                    wait: false,
+                }],
+            ),
+            (
+                "M190 S70",
+                &[Command::SetBedTemperature {
+                    target: 70.,
+                    wait: true,
+                }],
+            ),
+            ("M106", &[Command::SetFanSpeed(1.)]),
+            ("M106 S51", &[Command::SetFanSpeed(0.2)]),
+            ("M107", &[Command::SetFanSpeed(0.)]),
+        ]);
+        assert_error("M104", Error::MissingParameter('s'));
+        assert_error("M190 S", Error::MissingValue('s'));
+        assert_error("M106 S256", Error::InvalidValue('s', 256.));
+    }
+
+    #[test]
+    fn reports() {
+        assert_commands(&[
+            ("M105", &[Command::ReportTemperatures]),
+            ("M114", &[Command::ReportPosition]),
+            ("M115", &[Command::ReportFirmware]),
+            ("M110 N7", &[Command::SetLineNumber(7)]),
+        ]);
+        assert_error("M110", Error::MissingParameter('n'));
+    }
+
+    #[test]
+    fn unknown_commands() {
+        assert_error("G7", Error::UnknownCommand('g', 7.));
+        assert_error("G1.1 X1", Error::UnknownCommand('g', 1.1));
+        assert_error("M999", Error::UnknownCommand('m', 999.));
+        // Nothing after an error is processed.
+        assert_eq!(
+            process("G90 G7 G91", MotionMode::None),
+            [
+                Ok(Command::SetPositioning(Positioning::Absolute)),
+                Err(Error::UnknownCommand('g', 7.)),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_commands() {
+        assert_error("G", Error::MissingValue('g'));
+        assert_error("M", Error::MissingValue('m'));
+        // Not rounded to G0, G1.3 or M0.
+        assert_error("G-1 X1", Error::MalformedCommand('g', -1.));
+        assert_error("G1.25 X1", Error::MalformedCommand('g', 1.25));
+        assert_error("M-104 S200", Error::MalformedCommand('m', -104.));
+        assert_error("G59.01", Error::MalformedCommand('g', 59.01));
     }
 }
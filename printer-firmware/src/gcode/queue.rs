@@ -1,18 +1,192 @@
-pub struct Queue;
+//! Fixed capacity queue of the commands waiting for the motion to execute them.
 
+use core::cell::{Cell, RefCell};
+use core::task::Poll;
+
+use futures::future;
+use futures::task::AtomicWaker;
+
+#[derive(Debug)]
 pub enum Error<T> {
     QueueIsFull(T),
 }
 
-impl Queue {
-    pub fn push(&mut self, line: Vec<GCode>) -> Result<(), Error<Vec<GCode>>> {
-        unimplemented!()
+pub struct Queue<T, const N: usize> {
+    buffer: [Option<T>; N],
+    rd: usize,  // read ptr
+    len: usize, // number of queued elements
+}
+
+impl<T: Copy, const N: usize> Queue<T, N> {
+    pub fn new() -> Self {
+        Self {
+            buffer: [None; N],
+            rd: 0,
+            len: 0,
+        }
+    }
+}
+
+impl<T, const N: usize> Queue<T, N> {
+    /// Appends `item` to the queue, giving it back if there is no room left.
+    pub fn push(&mut self, item: T) -> Result<(), Error<T>> {
+        if self.is_full() {
+            return Err(Error::QueueIsFull(item));
+        }
+        let wr = (self.rd + self.len) % N;
+        self.buffer[wr] = Some(item);
+        self.len += 1;
+        Ok(())
     }
 
-    pub fn pop(&mut self, from: &State) -> Option<PartialState> {
-        unimplemented!()
+    /// Takes the oldest element out of the queue.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let item = self.buffer[self.rd].take();
+        self.rd = (self.rd + 1) % N;
+        self.len -= 1;
+        item
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
     pub fn is_full(&self) -> bool {
-        false
+        self.len == N
+    }
+}
+
+impl<T: Copy, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A [`Queue`] shared by tasks running on the same executor.
+pub struct SharedQueue<T, const N: usize> {
+    queue: RefCell<Queue<T, N>>,
+    /// no more element will be pushed
+    closed: Cell<bool>,
+    /// task waiting for an element to be pushed
+    pushed: AtomicWaker,
+    /// task waiting for an element to be popped
+    popped: AtomicWaker,
+}
+
+impl<T: Copy, const N: usize> SharedQueue<T, N> {
+    pub fn new() -> Self {
+        Self {
+            queue: RefCell::new(Queue::new()),
+            closed: Cell::new(false),
+            pushed: AtomicWaker::new(),
+            popped: AtomicWaker::new(),
+        }
+    }
+}
+
+impl<T, const N: usize> SharedQueue<T, N> {
+    /// Resolves once there is room for at least one more element.
+    pub async fn room(&self) {
+        future::poll_fn(|cx| {
+            self.popped.register(cx.waker());
+            if self.queue.borrow().is_full() {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await
+    }
+
+    /// Appends `item` to the queue, waiting for room if needed.
+    pub async fn push(&self, item: T) {
+        self.room().await;
+        self.queue
+            .borrow_mut()
+            .push(item)
+            .unwrap_or_else(|_| unreachable!());
+        self.pushed.wake();
+    }
+
+    /// Resolves to the oldest element of the queue, or `None` once it is closed and empty.
+    pub async fn pop(&self) -> Option<T> {
+        let item = future::poll_fn(|cx| {
+            self.pushed.register(cx.waker());
+            match self.queue.borrow_mut().pop() {
+                Some(item) => Poll::Ready(Some(item)),
+                None if self.closed.get() => Poll::Ready(None),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+        self.popped.wake();
+        item
+    }
+
+    /// Signals that no more element will be pushed.
+    pub fn close(&self) {
+        self.closed.set(true);
+        self.pushed.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    const N: usize = 3;
+
+    #[test]
+    fn empty() {
+        let mut queue = Queue::<u8, N>::new();
+        assert!(queue.is_empty());
+        assert!(!queue.is_full());
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn full() {
+        let mut queue = Queue::<u8, N>::new();
+        for i in 0..N as u8 {
+            assert!(queue.push(i).is_ok());
+        }
+        assert!(queue.is_full());
+        match queue.push(42) {
+            Err(Error::QueueIsFull(item)) => assert_eq!(item, 42),
+            Ok(()) => panic!("pushed to a full queue"),
+        }
+        for i in 0..N as u8 {
+            assert_eq!(queue.pop(), Some(i));
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut queue = Queue::<u8, N>::new();
+        assert!(queue.push(0).is_ok());
+        assert!(queue.push(1).is_ok());
+        // The read and write positions go round the buffer several times.
+        for i in 0..4 * N as u8 {
+            assert!(queue.push(i + 2).is_ok());
+            assert!(queue.is_full());
+            assert_eq!(queue.pop(), Some(i));
+        }
+        assert_eq!(queue.pop(), Some(4 * N as u8));
+        assert_eq!(queue.pop(), Some(4 * N as u8 + 1));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn shared_queue_ends_once_closed_and_empty() {
+        let queue = SharedQueue::<u8, N>::new();
+        assert_eq!(queue.pop().now_or_never(), None);
+        assert_eq!(queue.push(1).now_or_never(), Some(()));
+        queue.close();
+        assert_eq!(queue.pop().now_or_never(), Some(Some(1)));
+        assert_eq!(queue.pop().now_or_never(), Some(None));
     }
 }
@@ -0,0 +1,183 @@
+//! Conversion between the position of the tool and the position of the motors.
+//!
+//! Tool positions are cartesian coordinates in mm. Motor positions are in mm of travel of each
+//! motor, in the `[A, B, C]` order which [`crate::config::STEPS_PER_MM`] gives the resolution of
+//! along with the extruder's. The extruder is not affected by the kinematics.
+
+use crate::state::Workspace;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kinematics {
+    /// Each motor drives one axis.
+    Cartesian,
+    /// A and B drive X and Y together: X moves when both turn the same way, Y when they turn
+    /// opposite ways.
+    CoreXY,
+    /// Like CoreXY for X and Z, B drives Y.
+    CoreXZ,
+    /// Three carriages running up vertical towers, each one linked to the effector by a pair of
+    /// arms of length `diagonal`. The towers stand at `radius` from the center, measured between
+    /// the carriages' and the effector's joints, at 210°, 330° and 90° around the Z axis.
+    LinearDelta { diagonal: f32, radius: f32 },
+}
+
+type Vector = [f32; 3];
+
+fn add(a: Vector, b: Vector) -> Vector {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+fn sub(a: Vector, b: Vector) -> Vector {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+fn dot(a: Vector, b: Vector) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+fn cross(a: Vector, b: Vector) -> Vector {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+fn scale(a: Vector, k: f32) -> Vector {
+    [a[0] * k, a[1] * k, a[2] * k]
+}
+fn normalize(a: Vector) -> Vector {
+    scale(a, 1. / libm::sqrtf(dot(a, a)))
+}
+
+/// Position of the towers in the XY plane.
+fn towers(radius: f32) -> [[f32; 2]; 3] {
+    let angles = [210f32, 330., 90.];
+    let mut towers = [[0.; 2]; 3];
+    for (tower, angle) in towers.iter_mut().zip(angles.iter()) {
+        let angle = angle.to_radians();
+        *tower = [radius * libm::cosf(angle), radius * libm::sinf(angle)];
+    }
+    towers
+}
+
+impl Kinematics {
+    /// Whether straight moves of the tool are straight moves of the motors too. If not, moves must
+    /// be cut into short pieces to follow a straight line.
+    pub fn is_linear(&self) -> bool {
+        !matches!(self, Kinematics::LinearDelta { .. })
+    }
+
+    /// The motor positions placing the tool at `tool`, if it can reach it.
+    pub fn inverse(&self, tool: Workspace<f32>) -> Option<[f32; 3]> {
+        let Workspace { x, y, z } = tool;
+        match *self {
+            Kinematics::Cartesian => Some([x, y, z]),
+            Kinematics::CoreXY => Some([x + y, x - y, z]),
+            Kinematics::CoreXZ => Some([x + z, y, x - z]),
+            Kinematics::LinearDelta { diagonal, radius } => {
+                let mut carriages = [0.; 3];
+                for (carriage, tower) in carriages.iter_mut().zip(towers(radius).iter()) {
+                    let (dx, dy) = (x - tower[0], y - tower[1]);
+                    let height = diagonal * diagonal - dx * dx - dy * dy;
+                    if height < 0. {
+                        return None;
+                    }
+                    *carriage = z + libm::sqrtf(height);
+                }
+                Some(carriages)
+            }
+        }
+    }
+
+    /// The tool position for the motor positions `motors`.
+    #[cfg_attr(not(feature = "platform-sim"), allow(dead_code))]
+    pub fn forward(&self, motors: [f32; 3]) -> Option<Workspace<f32>> {
+        let [a, b, c] = motors;
+        let [x, y, z] = match *self {
+            Kinematics::Cartesian => [a, b, c],
+            Kinematics::CoreXY => [(a + b) / 2., (a - b) / 2., c],
+            Kinematics::CoreXZ => [(a + c) / 2., b, (a - c) / 2.],
+            Kinematics::LinearDelta { diagonal, radius } => {
+                // The effector is where the spheres of radius `diagonal` centered on the
+                // carriages meet, below them.
+                let towers = towers(radius);
+                let p = |i: usize| [towers[i][0], towers[i][1], motors[i]];
+                let (p1, p2, p3) = (p(0), p(1), p(2));
+
+                let d = libm::sqrtf(dot(sub(p2, p1), sub(p2, p1)));
+                let ex = normalize(sub(p2, p1));
+                let i = dot(ex, sub(p3, p1));
+                let ey = normalize(sub(sub(p3, p1), scale(ex, i)));
+                let ez = cross(ex, ey);
+                let j = dot(ey, sub(p3, p1));
+
+                let x = d / 2.;
+                let y = (i * i + j * j - 2. * i * x) / (2. * j);
+                let z2 = diagonal * diagonal - x * x - y * y;
+                if z2 < 0. {
+                    return None;
+                }
+                let z = libm::sqrtf(z2);
+                let base = add(p1, add(scale(ex, x), scale(ey, y)));
+                let (above, below) = (add(base, scale(ez, z)), sub(base, scale(ez, z)));
+                if above[2] < below[2] {
+                    above
+                } else {
+                    below
+                }
+            }
+        };
+        Some(Workspace { x, y, z })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const DELTA: Kinematics = Kinematics::LinearDelta {
+        diagonal: 250.,
+        radius: 120.,
+    };
+
+    fn assert_close(a: [f32; 3], b: [f32; 3], tolerance: f32) {
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!(libm::fabsf(a - b) <= tolerance, "{:?} != {:?}", a, b);
+        }
+    }
+
+    fn tool([x, y, z]: [f32; 3]) -> Workspace<f32> {
+        Workspace { x, y, z }
+    }
+
+    #[test]
+    fn delta_center() {
+        let height = libm::sqrtf(250. * 250. - 120. * 120.);
+        let carriages = DELTA.inverse(tool([0., 0., 10.])).unwrap();
+        assert_close(carriages, [10. + height; 3], 1e-3);
+        // Out of the arms' reach.
+        assert_eq!(DELTA.inverse(tool([0., 400., 0.])), None);
+        assert_eq!(DELTA.forward([0., 0., 1000.]), None);
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips(x in -100f32..100., y in -100f32..100., z in 0f32..200.) {
+            for kinematics in &[Kinematics::Cartesian, Kinematics::CoreXY, Kinematics::CoreXZ] {
+                let motors = kinematics.inverse(tool([x, y, z])).unwrap();
+                let back = kinematics.forward(motors).unwrap();
+                assert_close([back.x, back.y, back.z], [x, y, z], 1e-3);
+                // The same coordinates, taken as motor positions this time.
+                let motors = [x, y, z];
+                let position = kinematics.forward(motors).unwrap();
+                assert_close(kinematics.inverse(position).unwrap(), motors, 1e-3);
+            }
+        }
+
+        #[test]
+        fn delta_round_trips(x in -80f32..80., y in -80f32..80., z in 0f32..200.) {
+            let carriages = DELTA.inverse(tool([x, y, z])).unwrap();
+            let back = DELTA.forward(carriages).unwrap();
+            assert_close([back.x, back.y, back.z], [x, y, z], 1e-2);
+        }
+    }
+}
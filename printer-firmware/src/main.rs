@@ -1,74 +1,58 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(feature = "platform-sim"), no_std)]
+#![cfg_attr(not(feature = "platform-sim"), no_main)]
 //#![feature(alloc_error_handler)]
 
 extern crate async_gcode;
+#[cfg(not(feature = "platform-sim"))]
 extern crate panic_halt;
 //extern crate panic_semihosting;
 //use alloc_cortex_m::CortexMHeap;
 
+mod arc;
+mod bezier;
+mod config;
 mod executor;
 mod gcode;
+mod kinematics;
+mod mesh;
+mod motion;
+mod planner;
 mod platform;
+mod ring_buffer;
+mod serial;
+mod state;
+mod stepper;
+mod time;
+
+use core::cell::Cell;
+use core::task::Poll;
 
 use arrayvec::ArrayVec;
+#[cfg(not(feature = "platform-sim"))]
 use cortex_m_rt::entry;
-use embedded_hal::serial::Read;
 use futures::{future, stream, StreamExt, TryStreamExt};
+use pin_utils::pin_mut;
 
-enum Positioning {
-    Relative,
-    Absolute,
-}
-
-enum MotionMode {
-    RapidLinear,
-    Linear,
-    ClockwiseControledArc,
-    CounterClockwiseControledArc,
-    BezierCubicSpline,
-    None,
-}
-/*,
-    Homing,
-    BedLeveling,
-*/
-enum Unit {
-    Millimeter,
-    Inch,
-}
+use config::{MESH_POINTS_X, TRAVEL_MAX, TRAVEL_MIN};
+use executor::{Executor, Task};
 
-struct Workspace<T> {
-    x: T,
-    y: T,
-    z: T,
-}
-enum Plane {
-    XY,
-    YZ,
-    XZ,
-}
+use gcode::processor::{Command, Processor};
+use gcode::queue::SharedQueue;
+use motion::{Motion, Outcome};
+use platform::Platform;
+use serial::TxSink;
+use state::State;
 
-//struct State {
-//workspaces: [Workspace<f32>; 10],
-//current_workspace: u8,
-//place: Plane,
-//unit: Unit,
-//motion_mode: MotionMode,
-//positioning: Positioning,
-//axis_homed: Workspace<bool>,
-//stepper_on: bool,
-//hotend_temperature_target: Option<f32>,
-//hotbed_temperature_target: Option<f32>,
-//fan_speed: Option<f32>,
-//}
+/// Number of commands that can wait for the motion to execute them.
+const MOTION_QUEUE_DEPTH: usize = 16;
 
+/// Reasons for asking the host to resend a line, only ever read through `Debug` to be reported.
 #[derive(Debug)]
+#[allow(dead_code)]
 enum Error<IoError> {
     Io(IoError),
     Parsing(async_gcode::Error),
     InvalidLineNumber(u32),
-    TooManyWords,
 }
 impl<IoError> From<async_gcode::Error> for Error<IoError> {
     fn from(e: async_gcode::Error) -> Self {
@@ -76,79 +60,153 @@ impl<IoError> From<async_gcode::Error> for Error<IoError> {
     }
 }
 
-struct SerialIterator<B> {
-    reader: B,
-    buffer: [u8; 128], // this would be nicer with a const generic parameter
-    wr: usize,         // write ptr
-    rd: usize,         // read ptr
-}
-impl<B: Read<u8>> SerialIterator<B> {
-    fn new(reader: B) -> Self {
-        Self {
-            reader,
-            buffer: [0; 128],
-            wr: 0,
-            rd: 127,
-        }
-    }
-}
-impl<B> core::fmt::Debug for SerialIterator<B> {
-    fn fmt(&self, f: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
-        f.debug_struct("SerialIterator")
-            .field("buffer", &self.buffer)
-            .field("wr", &self.wr)
-            .field("rd", &self.rd)
-            .finish()
-    }
+/*#[global_allocator]
+static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+
+#[alloc_error_handler]
+fn oom(_: core::alloc::Layout) -> ! {
+    loop {}
+}*/
+
+async fn report_firmware(tx: &mut TxSink, platform_name: &str) -> core::fmt::Result {
+    writeln!(
+        tx,
+        "Rusty ({}) VER:{} MODEL:from_config HW:{}",
+        env!("CARGO_PKG_HOMEPAGE"),
+        env!("CARGO_PKG_VERSION"),
+        platform_name
+    )
+    .await
+    // cap:<capability name in caps>:<0 or 1>
+    //     AUTOREPORT_TEMP
+    //     AUTOREPORT_SD_STATUS
+    //     BUSY_PROTOCOL
+    //     EMERGENCY_PARSER
+    //     CHAMBER_TEMPERATURE
+    //     Marlin/src/gcode/host/M115.cpp
 }
 
-impl<B: Read<u8>> Iterator for SerialIterator<B> {
-    type Item = Result<u8, B::Error>;
-    fn next(&mut self) -> Option<Self::Item> {
-        while self.wr != self.rd {
-            match self.reader.read() {
-                Ok(byte) => {
-                    self.buffer[self.wr] = byte;
-                    self.wr += 1;
-                    if self.wr == 128 {
-                        self.wr = 0
-                    }
-                }
-                Err(nb::Error::WouldBlock) => break,
-                Err(nb::Error::Other(e)) => return Some(Err(e)),
+/// Sends the response to the reporting commands.
+async fn report(
+    tx: &mut TxSink,
+    cmd: &Command,
+    state: &State,
+    platform: &impl Platform,
+) -> core::fmt::Result {
+    match cmd {
+        Command::ReportTemperatures => {
+            // There is no temperature sensor support yet, only the targets are meaningful.
+            let target = |t: Option<f32>| t.unwrap_or(0.);
+            writeln!(
+                tx,
+                "T:0.0 /{:.1} B:0.0 /{:.1}",
+                target(state.hotend_temperature_target),
+                target(state.hotbed_temperature_target)
+            )
+            .await
+        }
+        Command::ReportPosition => {
+            let position = state.work_position().map(|v| state.in_unit(v));
+            writeln!(
+                tx,
+                "X:{:.2} Y:{:.2} Z:{:.2} E:{:.2}",
+                position.x,
+                position.y,
+                position.z,
+                state.in_unit(state.extruder_position)
+            )
+            .await
+        }
+        Command::ReportWorkspace(workspace) => {
+            let workspace = workspace.unwrap_or(state.current_workspace);
+            let offset = state.workspaces[usize::from(workspace)].map(|v| state.in_unit(v));
+            match workspace {
+                0..=5 => write!(tx, "G{}", 54 + workspace).await?,
+                _ => write!(tx, "G59.{}", workspace - 5).await?,
             }
+            writeln!(tx, " X:{:.2} Y:{:.2} Z:{:.2}", offset.x, offset.y, offset.z).await?;
+            let offset = state.position_offset.map(|v| state.in_unit(v));
+            writeln!(
+                tx,
+                "G92 X:{:.2} Y:{:.2} Z:{:.2}",
+                offset.x, offset.y, offset.z
+            )
+            .await
         }
-
-        let mut next_rd = self.rd + 1;
-        if next_rd == 128 {
-            next_rd = 0;
+        Command::SetSoftLimits(_) => {
+            let limit = |limits: [f32; 3], axis: usize| state.in_unit(limits[axis]);
+            writeln!(
+                tx,
+                "Soft endstops: {} Min: X:{:.2} Y:{:.2} Z:{:.2} Max: X:{:.2} Y:{:.2} Z:{:.2}",
+                if state.soft_limits { "On" } else { "Off" },
+                limit(TRAVEL_MIN, 0),
+                limit(TRAVEL_MIN, 1),
+                limit(TRAVEL_MIN, 2),
+                limit(TRAVEL_MAX, 0),
+                limit(TRAVEL_MAX, 1),
+                limit(TRAVEL_MAX, 2)
+            )
+            .await
+        }
+        Command::SetLeveling { report: true, .. } => {
+            let mesh = match &state.mesh {
+                Some(mesh) => mesh,
+                None => return writeln!(tx, "Bed leveling: no mesh").await,
+            };
+            let on = if state.leveling { "On" } else { "Off" };
+            writeln!(tx, "Bed leveling: {}", on).await?;
+            // Rows from the back of the bed to the front, as seen from above.
+            for row in mesh.heights.iter().rev() {
+                for (column, height) in row.iter().enumerate() {
+                    let separator = if column + 1 < MESH_POINTS_X {
+                        " "
+                    } else {
+                        "\n"
+                    };
+                    write!(tx, "{:+.3}{}", state.in_unit(*height), separator).await?;
+                }
+            }
+            Ok(())
         }
-        if next_rd != self.wr {
-            self.rd = next_rd;
-            Some(Ok(self.buffer[next_rd]))
-        } else {
-            None
+        Command::ReportFirmware => {
+            report_firmware(tx, platform.name()).await?;
+            writeln!(tx, "SYSCLK:{}", platform.sysclk()).await?;
+            let rx = platform.rx_stats();
+            writeln!(
+                tx,
+                "RX_BUFFER:{} HIGH_WATER_MARK:{} DROPPED:{}",
+                serial::RX_BUFFER_SIZE,
+                rx.high_water_mark,
+                rx.dropped
+            )
+            .await
         }
+        _ => Ok(()),
     }
 }
 
-/*#[global_allocator]
-static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
-
-#[alloc_error_handler]
-fn oom(_: core::alloc::Layout) -> ! {
-    loop {}
-}*/
+/// Asks the host to send the lines again starting from `line_number`.
+async fn resend(
+    tx: &mut TxSink,
+    error: &impl core::fmt::Debug,
+    line_number: u32,
+) -> core::fmt::Result {
+    writeln!(tx, "error: {:?}", error).await?;
+    writeln!(tx, "Resend: {}", line_number).await?;
+    writeln!(tx, "ok").await
+}
 
-#[entry]
+#[cfg_attr(not(feature = "platform-sim"), entry)]
 fn main() -> ! {
-    use core::fmt::Write;
+    let mut board = platform::Board::take();
+    run(&mut board);
+    board.shutdown()
+}
 
-    let platform::Platform {
-        sin: rx,
-        sout: mut tx,
-        name: platform_name,
-    } = platform::Platform::take();
+/// Runs the firmware on `platform` until its input is closed and everything it received is
+/// executed, which only ever happens on the host.
+fn run<P: Platform>(platform: &mut P) {
+    let (mut rx, mut tx) = platform.serial();
 
     // Initialize the allocator BEFORE you use it
     /*let start = cortex_m_rt::heap_start() as usize;
@@ -156,33 +214,38 @@ fn main() -> ! {
     unsafe { ALLOCATOR.init(start, size) }
     */
 
-    let mut it = SerialIterator::new(rx);
-    let strm = stream::poll_fn(|_| match it.next() {
-        Some(b) => core::task::Poll::Ready(Some(b)),
-        None => core::task::Poll::Pending,
+    // The input can only end on the host, flag it so that the firmware can wind down.
+    let input_closed = &Cell::new(false);
+    let strm = stream::poll_fn(move |cx| {
+        let res = rx.poll_next_unpin(cx);
+        if let Poll::Ready(None) = res {
+            input_closed.set(true);
+        }
+        res
     })
     .map(|res| res.map_err(Error::Io));
 
-    executor::block_on(async move {
+    let queue = SharedQueue::<Command, MOTION_QUEUE_DEPTH>::new();
+    let queue = &queue;
+
+    let homing = &Outcome::new();
+    let probing = &Outcome::new();
+
+    let mut motion = Motion::new(platform.steppers());
+    let platform = &*platform;
+    let motion = async move {
+        motion.run(queue, homing, probing).await;
+    };
+
+    let intake = async move {
         let mut parser = async_gcode::Parser::new(strm);
 
-        writeln!(
-            tx,
-            "Rusty ({}) VER:{} MODEL:{} HW:{}",
-            env!("CARGO_PKG_HOMEPAGE"),
-            env!("CARGO_PKG_VERSION"),
-            "from_config",
-            platform_name
-        )
-        .unwrap_or(());
-        // cap:<capability name in caps>:<0 or 1>
-        //     AUTOREPORT_TEMP
-        //     AUTOREPORT_SD_STATUS
-        //     BUSY_PROTOCOL
-        //     EMERGENCY_PARSER
-        //     CHAMBER_TEMPERATURE
-        //     Marlin/src/gcode/host/M115.cpp
+        report_firmware(&mut tx, platform.name())
+            .await
+            .unwrap_or(());
 
+        let processor = Processor::new();
+        let mut state = State::new();
         let mut next_line_number = 0;
         let mut error_recovery = false;
         loop {
@@ -191,12 +254,7 @@ fn main() -> ! {
                 &mut parser,
                 |p| async move { p.next().await.map(|w| (w, p)) },
             )
-            .take_while(|res| {
-                future::ready(match res {
-                    Ok(async_gcode::GCode::Execute) => false,
-                    _ => true,
-                })
-            })
+            .take_while(|res| future::ready(!matches!(res, Ok(async_gcode::GCode::Execute))))
             .take(10)
             .try_collect();
 
@@ -214,11 +272,87 @@ fn main() -> ! {
                             "error: Too many segment on the line (limit: {})",
                             segments.capacity()
                         )
+                        .await
                         .unwrap_or(());
                         error_recovery = true;
                     } else if !segments.is_empty() {
-                        writeln!(tx, "ok {:?}", segments).unwrap_or(());
-                        next_line_number += 1;
+                        let commands: Result<ArrayVec<[_; 10]>, _> =
+                            processor.process(&segments, state.motion_mode).collect();
+
+                        // A line carrying an M110 is accepted whatever its number is.
+                        let line_number = segments.iter().find_map(|word| match word {
+                            async_gcode::GCode::LineNumber(n) => Some(*n),
+                            _ => None,
+                        });
+                        let sets_line_number = match &commands {
+                            Ok(commands) => commands
+                                .iter()
+                                .any(|cmd| matches!(cmd, Command::SetLineNumber(_))),
+                            Err(_) => false,
+                        };
+                        match line_number {
+                            Some(n) if n != next_line_number && !sets_line_number => {
+                                resend(
+                                    &mut tx,
+                                    &Error::<()>::InvalidLineNumber(n),
+                                    next_line_number,
+                                )
+                                .await
+                                .unwrap_or(());
+                                continue;
+                            }
+                            Some(n) => next_line_number = n.wrapping_add(1),
+                            None => {}
+                        }
+
+                        match commands {
+                            Ok(commands) => {
+                                for cmd in &commands {
+                                    if let Command::SetLineNumber(n) = cmd {
+                                        next_line_number = n.wrapping_add(1);
+                                    }
+                                    match state.apply(cmd) {
+                                        Ok(Some(motion)) => {
+                                            queue.push(motion).await;
+                                            // What follows depends on the outcome of homing
+                                            // and probing.
+                                            let outcome = match motion {
+                                                Command::Home { x, y, z } => {
+                                                    let outcome = homing.wait().await;
+                                                    if let Err(motion::Error::EndstopNotFound(
+                                                        axis,
+                                                    )) = outcome
+                                                    {
+                                                        state.homing_failed(x, y, z, axis);
+                                                    }
+                                                    outcome
+                                                }
+                                                Command::ProbeMesh => probing
+                                                    .wait()
+                                                    .await
+                                                    .map(|mesh| state.set_mesh(mesh)),
+                                                _ => Ok(()),
+                                            };
+                                            if let Err(e) = outcome {
+                                                writeln!(tx, "error: {:?}", e).await.unwrap_or(());
+                                                break;
+                                            }
+                                        }
+                                        Ok(None) => {}
+                                        Err(e) => {
+                                            // The rest of the line is dropped too.
+                                            writeln!(tx, "error: {:?}", e).await.unwrap_or(());
+                                            break;
+                                        }
+                                    }
+                                    report(&mut tx, cmd, &state, platform).await.unwrap_or(());
+                                }
+                            }
+                            Err(e) => writeln!(tx, "error: {:?}", e).await.unwrap_or(()),
+                        }
+                        // Only acknowledge the line once there is room for the next one.
+                        queue.room().await;
+                        writeln!(tx, "ok").await.unwrap_or(());
                     }
                 }
                 Ok(segments) => {
@@ -227,16 +361,267 @@ fn main() -> ! {
                     }
                 }
                 Err(e) => {
-                    writeln!(tx, "wait: Err({:?})", e).unwrap_or(());
-                    writeln!(tx, "rs: N:{}", next_line_number).unwrap_or(());
+                    // The line got corrupted on its way, drop what is left of it.
+                    resend(&mut tx, &e, next_line_number).await.unwrap_or(());
                     error_recovery = true;
                 }
             }
 
-            // if err.is_some() ignore the words
-            // if first == LineNumber => check line number
-            // process words
+            if input_closed.get() {
+                break;
+            }
+        }
+        queue.close();
+    };
+
+    pin_mut!(intake);
+    pin_mut!(motion);
+    let mut tasks = [Task::new(intake), Task::new(motion)];
+    Executor::new(&mut tasks).run(|| platform.idle());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use platform::mock::Mock;
+
+    #[test]
+    fn runs_on_a_mock_platform() {
+        let mut mock = Mock::new("M115\nG91\nG1 X5 Y-2 Z1\nM114\n", [10.; 3], [0.; 3]);
+        run(&mut mock);
+        let output = mock.output();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 9);
+        assert!(lines[0].ends_with("HW:mock"));
+        assert!(lines[1].ends_with("HW:mock"));
+        assert_eq!(
+            lines[2..],
+            [
+                "SYSCLK:1000000000",
+                "RX_BUFFER:128 HIGH_WATER_MARK:0 DROPPED:0",
+                "ok",
+                "ok",
+                "ok",
+                "X:5.00 Y:-2.00 Z:1.00 E:0.00",
+                "ok"
+            ]
+        );
+        let tool = mock.tool();
+        assert_eq!((tool.x, tool.y, tool.z), (15., 8., 11.));
+    }
+
+    #[test]
+    fn dwells_on_a_virtual_clock() {
+        let start = time::Instant::now();
+        let script = "G91\nG1 X5\nG4 P1500\nG4 S2\nG1 X5\nM114\n";
+        let mut mock = Mock::new(script, [10.; 3], [0.; 3]);
+        run(&mut mock);
+        let output = mock.output();
+        let lines: Vec<_> = output.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                "ok",
+                "ok",
+                "ok",
+                "ok",
+                "ok",
+                "X:10.00 Y:0.00 Z:0.00 E:0.00",
+                "ok"
+            ]
+        );
+        assert_eq!(mock.tool().x, 20.);
+        assert_eq!(start.elapsed(), core::time::Duration::from_millis(3500));
+    }
+
+    /// `line` numbered `n`, followed by its checksum.
+    fn numbered(n: u32, line: &str) -> String {
+        let line = format!("N{} {}", n, line);
+        let checksum = line.bytes().fold(0, |checksum, b| checksum ^ b);
+        format!("{}*{}\n", line, checksum)
+    }
+
+    #[test]
+    fn asks_for_corrupted_lines_again() {
+        let corrupted = numbered(1, "G1 X1").replace("X1", "X7");
+        let script = [
+            numbered(0, "G91"),
+            corrupted,
+            numbered(1, "G1 X1"),
+            // Line 2 got lost.
+            numbered(3, "G1 X1"),
+            numbered(2, "G1 X1"),
+            numbered(3, "M114"),
+        ]
+        .concat();
+        let mut mock = Mock::new(&script, [10.; 3], [0.; 3]);
+        run(&mut mock);
+        let output = mock.output();
+        let lines: Vec<_> = output.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                "ok",
+                "error: Parsing(BadChecksum)",
+                "Resend: 1",
+                "ok",
+                "ok",
+                "error: InvalidLineNumber(3)",
+                "Resend: 2",
+                "ok",
+                "ok",
+                "X:2.00 Y:0.00 Z:0.00 E:0.00",
+                "ok",
+            ]
+        );
+        assert_eq!(mock.tool().x, 12.);
+    }
+
+    #[test]
+    fn resets_the_line_number() {
+        let script = [
+            numbered(7, "M110"),
+            numbered(8, "M114"),
+            numbered(0, "M114"),
+        ]
+        .concat();
+        let mut mock = Mock::new(&script, [10.; 3], [0.; 3]);
+        run(&mut mock);
+        let output = mock.output();
+        let lines: Vec<_> = output.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                "ok",
+                "X:0.00 Y:0.00 Z:0.00 E:0.00",
+                "ok",
+                "error: InvalidLineNumber(0)",
+                "Resend: 9",
+                "ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn homes_against_the_endstops() {
+        for start in &[[10., 20., 30.], [0., 150., 0.5], [199., 3., 179.]] {
+            let mut mock = Mock::new("G28\nM114\n", *start, [0.; 3]);
+            run(&mut mock);
+            let output = mock.output();
+            let lines: Vec<_> = output.lines().skip(1).collect();
+            assert_eq!(lines, ["ok", "X:0.00 Y:0.00 Z:0.00 E:0.00", "ok"]);
+            // The endstops trigger as soon as the tool reaches 0.
+            let tool = mock.tool();
+            for position in &[tool.x, tool.y, tool.z] {
+                assert!(position.abs() < 0.02, "{:?}: {:?}", start, tool);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_endstops_out_of_reach() {
+        // Z homes first, X's endstop is further than its homing travel.
+        let mut mock = Mock::new("G28\n", [300., 20., 30.], [0.; 3]);
+        run(&mut mock);
+        let output = mock.output();
+        let lines: Vec<_> = output.lines().skip(1).collect();
+        assert_eq!(lines, ["error: EndstopNotFound('X')", "ok"]);
+        let tool = mock.tool();
+        assert!(
+            tool.z.abs() < 0.02 && (tool.x - 50.).abs() < 0.02,
+            "{:?}",
+            tool
+        );
+        assert_eq!(tool.y, 20.);
+    }
+
+    #[test]
+    fn keeps_the_axes_homed_before_the_failure() {
+        // Z homes, X fails and Y, which comes after it, is left alone.
+        let script = "G28\nG1 Z5\nG1 Y5\nG1 X5\n";
+        let mut mock = Mock::new(script, [300., 20., 30.], [0.; 3]);
+        run(&mut mock);
+        let output = mock.output();
+        let lines: Vec<_> = output.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                "error: EndstopNotFound('X')",
+                "ok",
+                "ok",
+                "error: NotHomed('Y')",
+                "ok",
+                "error: NotHomed('X')",
+                "ok",
+            ]
+        );
+        let tool = mock.tool();
+        assert!((tool.z - 5.).abs() < 0.02, "{:?}", tool);
+        assert_eq!(tool.y, 20.);
+    }
+
+    #[test]
+    fn refuses_absolute_moves_before_homing() {
+        let script = "G1 X10\nG91\nG1 X10\nG90\nG28 X\nG1 X5\nG1 Y5\n";
+        let mut mock = Mock::new(script, [10.; 3], [0.; 3]);
+        run(&mut mock);
+        let output = mock.output();
+        let lines: Vec<_> = output.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                "error: NotHomed('X')",
+                "ok",
+                "ok",
+                "ok",
+                "ok",
+                "ok",
+                "ok",
+                "error: NotHomed('Y')",
+                "ok",
+            ]
+        );
+        let tool = mock.tool();
+        assert_eq!((tool.y, tool.z), (10., 10.));
+        assert!((tool.x - 5.).abs() < 0.02, "{:?}", tool);
+    }
+
+    #[test]
+    fn keeps_track_of_dropped_moves() {
+        // Toggling the leveling correction changes the motor positions of the current position,
+        // moving there again is too short to be planned.
+        let script = "G28\nG29\nM420 S1\nG1 X10 Y10 Z5\nM420 S0\nG1 X10 Y10 Z5\nG1 X0 Y0 Z0\n";
+        let mut mock = Mock::new(script, [10.; 3], [0.5, 0.01, 0.]);
+        run(&mut mock);
+        assert!(!mock.output().contains("error"), "{}", mock.output());
+        let tool = mock.tool();
+        for position in &[tool.x, tool.y, tool.z] {
+            assert!(position.abs() < 0.02, "{:?}", tool);
+        }
+    }
+
+    #[test]
+    fn drills_along_the_normal_of_the_plane() {
+        // Plane, hole and where the tool ends with G98 and with G99.
+        let cycles = [
+            ("G17", "X30 Y40 Z2", [30., 40., 20.], [30., 40., 10.]),
+            ("G18", "X30 Z40 Y2", [30., 20., 40.], [30., 10., 40.]),
+            ("G19", "Y30 Z40 X2", [20., 30., 40.], [10., 30., 40.]),
+        ];
+        for (plane, hole, initial, r) in &cycles {
+            for (retract, end) in &[("G98", initial), ("G99", r)] {
+                let script = format!(
+                    "G28\n{}\nG0 X20 Y20 Z20\n{}\nG81 {} R10\n",
+                    plane, retract, hole
+                );
+                let mut mock = Mock::new(&script, [10.; 3], [0.; 3]);
+                run(&mut mock);
+                assert!(!mock.output().contains("error"), "{}", mock.output());
+                let tool = mock.tool();
+                for (position, end) in [tool.x, tool.y, tool.z].iter().zip(end.iter()) {
+                    assert!((position - end).abs() < 0.02, "{}: {:?}", script, tool);
+                }
+            }
         }
-    });
-    unreachable!()
+    }
 }
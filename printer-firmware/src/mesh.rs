@@ -0,0 +1,212 @@
+//! Bed leveling mesh.
+//!
+//! G29 probes the height of the bed on a grid covering [`MESH_MIN`] to [`MESH_MAX`]. Moves are then
+//! corrected by the height of the bed under the tool, interpolated between the points of the grid,
+//! and cut where they cross its lines so that the correction follows the mesh. Outside of the grid,
+//! the height is the one at its nearest edge.
+
+use crate::config::{
+    Interpolation, MESH_INTERPOLATION, MESH_MAX, MESH_MIN, MESH_POINTS_X, MESH_POINTS_Y,
+};
+
+/// Number of grid lines along X and Y.
+const POINTS: [usize; 2] = [MESH_POINTS_X, MESH_POINTS_Y];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mesh {
+    /// Height of the bed at each point of the grid, by row along Y then column along X.
+    pub heights: [[f32; MESH_POINTS_X]; MESH_POINTS_Y],
+}
+
+/// Position of the grid line `index` along `axis`.
+fn line(axis: usize, index: usize) -> f32 {
+    let spacing = (MESH_MAX[axis] - MESH_MIN[axis]) / (POINTS[axis] - 1) as f32;
+    MESH_MIN[axis] + spacing * index as f32
+}
+
+/// Coordinates of the point of the grid at `column` and `row`.
+pub fn point(column: usize, row: usize) -> [f32; 2] {
+    [line(0, column), line(1, row)]
+}
+
+/// Ratios of the way from `start` to `end` at which the grid lines are crossed, unordered.
+pub fn crossings(start: [f32; 2], end: [f32; 2]) -> impl Iterator<Item = f32> {
+    (0..2).flat_map(move |axis| {
+        let delta = end[axis] - start[axis];
+        (0..POINTS[axis]).filter_map(move |index| {
+            let ratio = (line(axis, index) - start[axis]) / delta;
+            // Also rules out the NaNs of the moves parallel to the lines.
+            if ratio > 0. && ratio < 1. {
+                Some(ratio)
+            } else {
+                None
+            }
+        })
+    })
+}
+
+/// Cell of the grid containing `v` along `axis`, and where `v` is across it from 0 to 1. Positions
+/// outside of the grid are brought back to its edges.
+fn locate(axis: usize, v: f32) -> (usize, f32) {
+    let last = (POINTS[axis] - 1) as f32;
+    let spacing = (MESH_MAX[axis] - MESH_MIN[axis]) / last;
+    let position = ((v - MESH_MIN[axis]) / spacing).max(0.).min(last);
+    let cell = (position as usize).min(POINTS[axis] - 2);
+    (cell, position - cell as f32)
+}
+
+/// Catmull-Rom spline through `p[1]` at 0 and `p[2]` at 1.
+fn catmull_rom(p: [f32; 4], t: f32) -> f32 {
+    let [p0, p1, p2, p3] = p;
+    p1 + 0.5
+        * t
+        * (p2 - p0 + t * (2. * p0 - 5. * p1 + 4. * p2 - p3 + t * (3. * (p1 - p2) + p3 - p0)))
+}
+
+impl Mesh {
+    pub fn new() -> Self {
+        Self {
+            heights: [[0.; MESH_POINTS_X]; MESH_POINTS_Y],
+        }
+    }
+
+    /// Height of the bed at `x`, `y`.
+    pub fn height(&self, x: f32, y: f32) -> f32 {
+        self.interpolate(MESH_INTERPOLATION, x, y)
+    }
+
+    fn interpolate(&self, interpolation: Interpolation, x: f32, y: f32) -> f32 {
+        let (column, u) = locate(0, x);
+        let (row, v) = locate(1, y);
+        match interpolation {
+            Interpolation::Bilinear => {
+                let h = |c: usize, r: usize| self.heights[r][c];
+                let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+                lerp(
+                    lerp(h(column, row), h(column + 1, row), u),
+                    lerp(h(column, row + 1), h(column + 1, row + 1), u),
+                    v,
+                )
+            }
+            Interpolation::Bicubic => {
+                // The points around the cell, the edges of the grid being repeated past them.
+                // Indexes are one past the points' so as not to go negative.
+                let h = |c: usize, r: usize| {
+                    let c = (c.max(1) - 1).min(MESH_POINTS_X - 1);
+                    let r = (r.max(1) - 1).min(MESH_POINTS_Y - 1);
+                    self.heights[r][c]
+                };
+                let mut columns = [0.; 4];
+                for (i, height) in columns.iter_mut().enumerate() {
+                    let c = column + i;
+                    *height =
+                        catmull_rom([h(c, row), h(c, row + 1), h(c, row + 2), h(c, row + 3)], v);
+                }
+                catmull_rom(columns, u)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A mesh of the heights of `bed` at the points of the grid.
+    fn mesh(bed: impl Fn(f32, f32) -> f32) -> Mesh {
+        let mut mesh = Mesh::new();
+        for (row, heights) in mesh.heights.iter_mut().enumerate() {
+            for (column, height) in heights.iter_mut().enumerate() {
+                let [x, y] = point(column, row);
+                *height = bed(x, y);
+            }
+        }
+        mesh
+    }
+
+    /// An uneven bed.
+    fn bumpy(x: f32, y: f32) -> f32 {
+        libm::sinf(x / 40.) * libm::cosf(y / 25.) + x * y / 1e4
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn goes_through_the_grid() {
+        let mesh = mesh(bumpy);
+        for &interpolation in &[Interpolation::Bilinear, Interpolation::Bicubic] {
+            for (row, heights) in mesh.heights.iter().enumerate() {
+                for (column, height) in heights.iter().enumerate() {
+                    let [x, y] = point(column, row);
+                    assert_close(mesh.interpolate(interpolation, x, y), *height);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bilinear() {
+        let plane = |x: f32, y: f32| 0.5 + x / 200. - y / 300.;
+        let tilted = mesh(plane);
+        for &(x, y) in &[(10., 10.), (33., 171.), (100., 100.), (189.9, 12.5)] {
+            assert_close(
+                tilted.interpolate(Interpolation::Bilinear, x, y),
+                plane(x, y),
+            );
+        }
+
+        let mesh = mesh(bumpy);
+        // Halfway across a cell, the average of its corners.
+        let [x0, y0] = point(1, 2);
+        let [x1, y1] = point(2, 3);
+        let corners =
+            mesh.heights[2][1] + mesh.heights[2][2] + mesh.heights[3][1] + mesh.heights[3][2];
+        let height = mesh.interpolate(Interpolation::Bilinear, (x0 + x1) / 2., (y0 + y1) / 2.);
+        assert_close(height, corners / 4.);
+        // Linear along the lines of the grid.
+        let [x, y] = point(1, 1);
+        let height = mesh.interpolate(Interpolation::Bilinear, x + (x1 - x0) / 4., y);
+        assert_close(
+            height,
+            0.75 * mesh.heights[1][1] + 0.25 * mesh.heights[1][2],
+        );
+    }
+
+    #[test]
+    fn bicubic() {
+        // Catmull-Rom splines follow straight lines, away from the edges of the grid where their
+        // end points are repeated.
+        let plane = |x: f32, y: f32| 0.5 + x / 200. - y / 300.;
+        let tilted = mesh(plane);
+        let ([x0, y0], [x1, y1]) = (point(1, 1), point(2, 2));
+        for &(u, v) in &[(0.25, 0.5), (0.5, 0.5), (0.9, 0.1)] {
+            let (x, y) = (x0 + (x1 - x0) * u, y0 + (y1 - y0) * v);
+            assert_close(
+                tilted.interpolate(Interpolation::Bicubic, x, y),
+                plane(x, y),
+            );
+        }
+
+        // Along a grid line, the spline through the 4 points around the cell.
+        let mesh = mesh(bumpy);
+        let p = [0, 1, 2, 3].map(|column| mesh.heights[1][column]);
+        let [x, y] = point(1, 1);
+        let height = mesh.interpolate(Interpolation::Bicubic, x + (x1 - x0) / 2., y);
+        assert_close(height, (-p[0] + 9. * p[1] + 9. * p[2] - p[3]) / 16.);
+    }
+
+    #[test]
+    fn flat_past_the_edges() {
+        let mesh = mesh(bumpy);
+        for &interpolation in &[Interpolation::Bilinear, Interpolation::Bicubic] {
+            let height = |x, y| mesh.interpolate(interpolation, x, y);
+            let [x, y] = point(2, 1);
+            assert_close(height(0., 0.), mesh.heights[0][0]);
+            assert_close(height(250., -50.), mesh.heights[0][MESH_POINTS_X - 1]);
+            assert_close(height(x, 200.), mesh.heights[MESH_POINTS_Y - 1][2]);
+            assert_close(height(-5., y), mesh.heights[1][0]);
+        }
+    }
+}
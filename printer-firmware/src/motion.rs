@@ -0,0 +1,572 @@
+//! Execution of the queued motion commands.
+//!
+//! Moves are converted from the tool's coordinates to the motors' ones by the configured
+//! [`KINEMATICS`] and handed over to the [`Planner`] which feeds step [`Segment`]s to the step
+//! generator. Arcs, Bézier curves and drilling cycles are run as a series of linear moves, and so
+//! are all moves, in short pieces, if the kinematics are not linear.
+//! Once a bed mesh was probed, the height of the bed under the tool is added to Z. Moves are then
+//! cut where they cross the lines of the mesh, and in short pieces if it is bicubic.
+//! Commands that must happen between two moves, like dwells or enabling the drivers, first wait
+//! for the planned moves to be cut into segments, bringing the machine to a stop.
+//!
+//! Homing looks for each axis' endstop at a fast pace, backs off from it and then locates it
+//! precisely at a slow pace, all at constant speeds. Probing lowers the probe on each point of the
+//! mesh grid until it touches the bed. Their outcome is handed back through an [`Outcome`] so that
+//! no command depending on it gets interpreted before it is known.
+
+use core::cell::Cell;
+use core::cmp::Ordering;
+use core::task::Poll;
+use core::time::Duration;
+
+use arrayvec::ArrayVec;
+use futures::future::{self, Either};
+use futures::task::AtomicWaker;
+use pin_utils::pin_mut;
+
+use crate::arc::{self, Arc};
+use crate::bezier::Bezier;
+use crate::config::{
+    Interpolation, ARC_TOLERANCE, AXIS_COUNT, AXIS_NAMES, BEZIER_TOLERANCE, HOMING_BACK_OFF,
+    HOMING_FAST_SPEED, HOMING_ORDER, HOMING_SLOW_SPEED, HOMING_TRAVEL, KINEMATICS,
+    KINEMATIC_SEGMENT_LENGTH, MESH_INTERPOLATION, MESH_POINTS_X, MESH_POINTS_Y, PROBE_CLEARANCE,
+    PROBE_SPEED, PROBE_TRAVEL, STEPS_PER_MM,
+};
+use crate::gcode::processor::{ArcDirection, Command, MoveType};
+use crate::gcode::queue::SharedQueue;
+use crate::mesh::{self, Mesh};
+use crate::planner::Planner;
+use crate::state::{Plane, Workspace};
+use crate::stepper::{step_events, Segment, SegmentSink, Switch, STEP_TIMER_HZ};
+use crate::time::Timer;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The endstop of the axis was not found within its homing travel.
+    EndstopNotFound(char),
+    /// The probe didn't touch the bed within its travel.
+    BedNotFound,
+}
+
+/// Outcome of the last homing or probing cycle, waited for by the G-code intake.
+pub struct Outcome<T> {
+    result: Cell<Option<Result<T, Error>>>,
+    waker: AtomicWaker,
+}
+
+impl<T> Outcome<T> {
+    pub fn new() -> Self {
+        Self {
+            result: Cell::new(None),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    fn finish(&self, result: Result<T, Error>) {
+        self.result.set(Some(result));
+        self.waker.wake();
+    }
+
+    /// Resolves once the cycle that was queued last is over.
+    pub async fn wait(&self) -> Result<T, Error> {
+        future::poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            match self.result.take() {
+                Some(result) => Poll::Ready(result),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+pub struct Motion {
+    sink: SegmentSink,
+    planner: Planner,
+    /// Position of the tool and of the extruder at the end of the planned moves, in mm.
+    tool: [f32; AXIS_COUNT],
+    /// Position of each motor at the end of the planned moves, in steps.
+    position: [i32; AXIS_COUNT],
+    /// Feed rate in mm/min.
+    feedrate: f32,
+    /// Plane the arcs are drawn in.
+    plane: Plane,
+    /// Last bed mesh probed.
+    mesh: Option<Mesh>,
+    /// Whether the moves are corrected by `mesh`.
+    leveling: bool,
+}
+
+fn to_steps(axis: usize, mm: f32) -> i32 {
+    libm::roundf(mm * STEPS_PER_MM[axis]) as i32
+}
+
+impl Motion {
+    pub fn new(sink: SegmentSink) -> Self {
+        let mut motion = Self {
+            sink,
+            planner: Planner::new(),
+            tool: [0.; AXIS_COUNT],
+            position: [0; AXIS_COUNT],
+            feedrate: 0.,
+            plane: Plane::XY,
+            mesh: None,
+            leveling: false,
+        };
+        motion.rebase();
+        motion
+    }
+
+    /// Executes the commands queued by [`crate::state::State::apply`], with absolute coordinates,
+    /// until the queue is closed and all the motion is done.
+    pub async fn run<const N: usize>(
+        &mut self,
+        queue: &SharedQueue<Command, N>,
+        homing: &Outcome<()>,
+        probing: &Outcome<Mesh>,
+    ) {
+        loop {
+            // Commands are taken first so that the planner looks as far ahead as possible, the
+            // planned moves are cut into segments while waiting for more.
+            let cmd = if self.planner.is_empty() {
+                Some(queue.pop().await)
+            } else if self.planner.is_full() {
+                None
+            } else {
+                let pop = queue.pop();
+                let room = self.sink.room();
+                pin_mut!(pop);
+                pin_mut!(room);
+                match future::select(pop, room).await {
+                    Either::Left((cmd, _)) => Some(cmd),
+                    Either::Right(_) => None,
+                }
+            };
+            match cmd {
+                // The step generator has room first.
+                None => self.next_segment().await,
+                Some(Some(Command::Home { x, y, z })) => homing.finish(self.home([x, y, z]).await),
+                Some(Some(Command::ProbeMesh)) => probing.finish(self.probe_mesh().await),
+                Some(Some(cmd)) => self.execute(cmd).await,
+                Some(None) => break,
+            }
+        }
+        self.flush().await;
+        self.sink.idle().await
+    }
+
+    async fn next_segment(&mut self) {
+        if let Some(segment) = self.planner.next_segment() {
+            self.sink.push(segment).await;
+        }
+    }
+
+    /// Cuts all the planned moves into segments.
+    async fn flush(&mut self) {
+        while !self.planner.is_empty() {
+            self.next_segment().await;
+        }
+    }
+
+    async fn execute(&mut self, cmd: Command) {
+        match cmd {
+            Command::LinearMove {
+                move_type,
+                x,
+                y,
+                z,
+                e,
+            } => self.linear(move_type, [x, y, z, e]).await,
+            Command::ArcMove {
+                direction,
+                x,
+                y,
+                z,
+                e,
+                i,
+                j,
+                k,
+                ..
+            } => {
+                let offset = |o: Option<f32>| o.unwrap_or(0.);
+                let offsets = [offset(i), offset(j), offset(k)];
+                self.arc(direction, [x, y, z, e], offsets).await
+            }
+            Command::BezierMove {
+                x,
+                y,
+                e,
+                i,
+                j,
+                p,
+                q,
+            } => {
+                let offset = |o: Option<f32>| o.unwrap_or(0.);
+                let control = [[offset(i), offset(j)], [offset(p), offset(q)]];
+                self.bezier([x, y, e], control).await
+            }
+            Command::Drill {
+                x: Some(x),
+                y: Some(y),
+                z: Some(z),
+                r: Some(r),
+                retract: Some(retract),
+            } => self.drill([x, y, z], r, retract).await,
+            Command::FeedRate(feedrate) => self.feedrate = feedrate,
+            Command::SetPlane(plane) => self.plane = plane,
+            // Like when disabled by homing or probing, the correction changes along the next move.
+            Command::SetLeveling {
+                enabled: Some(enabled),
+                ..
+            } => self.leveling = enabled && self.mesh.is_some(),
+            Command::Dwell(seconds) => {
+                self.flush().await;
+                self.sink.idle().await;
+                let us = (f64::from(seconds) * 1e6) as u64;
+                Timer::after(Duration::from_micros(us)).await
+            }
+            Command::SetPosition { x, y, z, e } => {
+                for (axis, v) in [x, y, z, e].iter().enumerate() {
+                    if let Some(v) = v {
+                        self.tool[axis] = *v;
+                    }
+                }
+                self.rebase();
+            }
+            Command::EnableSteppers => {
+                self.flush().await;
+                self.sink.push(Segment::Enable(true)).await
+            }
+            Command::DisableSteppers => {
+                self.flush().await;
+                self.sink.push(Segment::Enable(false)).await
+            }
+            _ => {}
+        }
+    }
+
+    /// Homes the selected X, Y and Z axes, in the configured order.
+    ///
+    /// The state only asks for homing with linear kinematics. The homed axes are at 0 afterwards,
+    /// even if homing failed, in which case they should not be trusted. The leveling correction is
+    /// disabled as the mesh was probed relative to the previous home.
+    async fn home(&mut self, axes: [bool; 3]) -> Result<(), Error> {
+        self.leveling = false;
+        let mut result = Ok(());
+        for &axis in HOMING_ORDER.iter().filter(|axis| axes[**axis]) {
+            let (fast, back_off) = (HOMING_FAST_SPEED[axis], HOMING_BACK_OFF[axis]);
+            let endstop = Some(Switch::Endstop(axis));
+            let mut found = self.seek(axis, -HOMING_TRAVEL[axis], fast, endstop).await;
+            if found {
+                self.seek(axis, back_off, fast, None).await;
+                found = self
+                    .seek(axis, -2. * back_off, HOMING_SLOW_SPEED[axis], endstop)
+                    .await;
+            }
+            if !found {
+                result = Err(Error::EndstopNotFound(AXIS_NAMES[axis]));
+                break;
+            }
+        }
+
+        for (axis, home) in axes.iter().enumerate() {
+            if *home {
+                self.tool[axis] = 0.;
+            }
+        }
+        self.rebase();
+        result
+    }
+
+    /// Probes the bed on the mesh grid, row after row in alternating directions, and enables the
+    /// leveling correction with the resulting mesh.
+    ///
+    /// The state only asks for probing once all the axes are homed, with linear kinematics. The
+    /// probe ends at the clearance height above where it started, even if probing failed.
+    async fn probe_mesh(&mut self) -> Result<Mesh, Error> {
+        self.leveling = false;
+        let [x, y, ..] = self.tool;
+        let mut mesh = Mesh::new();
+        let mut result = Ok(());
+        'grid: for row in 0..MESH_POINTS_Y {
+            for n in 0..MESH_POINTS_X {
+                let column = if row % 2 == 0 {
+                    n
+                } else {
+                    MESH_POINTS_X - 1 - n
+                };
+                let [px, py] = mesh::point(column, row);
+                self.linear(MoveType::Quick, [None, None, Some(PROBE_CLEARANCE), None])
+                    .await;
+                self.linear(MoveType::Quick, [Some(px), Some(py), None, None])
+                    .await;
+                match self.probe().await {
+                    Some(height) => mesh.heights[row][column] = height,
+                    None => {
+                        result = Err(Error::BedNotFound);
+                        break 'grid;
+                    }
+                }
+            }
+        }
+        self.linear(MoveType::Quick, [None, None, Some(PROBE_CLEARANCE), None])
+            .await;
+        self.linear(MoveType::Quick, [Some(x), Some(y), None, None])
+            .await;
+        result?;
+
+        self.mesh = Some(mesh);
+        self.leveling = true;
+        Ok(mesh)
+    }
+
+    /// Lowers the probe until it touches the bed, returning the height it did at.
+    async fn probe(&mut self) -> Option<f32> {
+        let touched = self
+            .seek(2, -PROBE_TRAVEL, PROBE_SPEED, Some(Switch::Probe))
+            .await;
+        if touched {
+            Some(self.tool[2])
+        } else {
+            None
+        }
+    }
+
+    /// Moves `axis` by `distance` at a constant `speed`, stopping early if `switch` is set and
+    /// triggers. Returns whether it did.
+    ///
+    /// Only meant for linear kinematics, which move the motors in proportion to the tool.
+    async fn seek(
+        &mut self,
+        axis: usize,
+        distance: f32,
+        speed: f32,
+        switch: Option<Switch>,
+    ) -> bool {
+        let mut delta = [0.; 3];
+        delta[axis] = distance;
+        let [x, y, z] = delta;
+        let motors = KINEMATICS
+            .inverse(Workspace { x, y, z })
+            .unwrap_or_else(|| unreachable!());
+        let mut steps = [0; AXIS_COUNT];
+        for (motor, v) in motors.iter().enumerate() {
+            steps[motor] = to_steps(motor, *v);
+        }
+
+        let duration = (libm::fabsf(distance) / speed * STEP_TIMER_HZ as f32) as u32;
+        let segment = match switch {
+            Some(switch) => Segment::seek(steps, duration, switch),
+            None => Segment::linear(steps, duration),
+        };
+        self.flush().await;
+        self.sink.push(segment).await;
+        self.sink.idle().await;
+
+        // The motors all stopped after the same share of their steps.
+        let triggered = switch.and_then(|_| self.sink.triggered());
+        let (performed, events) = match triggered {
+            Some(performed) => (i64::from(performed), i64::from(step_events(&steps))),
+            None => (1, 1),
+        };
+        for (p, s) in self.position.iter_mut().zip(steps.iter()) {
+            *p += (i64::from(*s) * performed / events) as i32;
+        }
+        self.tool[axis] += distance * performed as f32 / events as f32;
+        triggered.is_some()
+    }
+
+    /// Height of the bed under `x`, `y` that the moves are corrected by.
+    fn correction(&self, x: f32, y: f32) -> f32 {
+        match &self.mesh {
+            Some(mesh) if self.leveling => mesh.height(x, y),
+            _ => 0.,
+        }
+    }
+
+    /// Position of the motors placing the tool at `tool`, in steps, if it can be reached.
+    fn motors(&self, tool: [f32; AXIS_COUNT]) -> Option<[i32; AXIS_COUNT]> {
+        let [x, y, z, e] = tool;
+        let z = z + self.correction(x, y);
+        let [a, b, c] = KINEMATICS.inverse(Workspace { x, y, z })?;
+        let mut steps = [0; AXIS_COUNT];
+        for (axis, v) in [a, b, c, e].iter().enumerate() {
+            steps[axis] = to_steps(axis, *v);
+        }
+        Some(steps)
+    }
+
+    /// Derives the position of the motors from the position of the tool, after the latter was
+    /// overwritten.
+    fn rebase(&mut self) {
+        if let Some(position) = self.motors(self.tool) {
+            self.position = position;
+        }
+    }
+
+    async fn linear(&mut self, move_type: MoveType, target: [Option<f32>; AXIS_COUNT]) {
+        let start = self.tool;
+        let mut end = start;
+        let mut delta = [0f32; AXIS_COUNT];
+        for axis in 0..AXIS_COUNT {
+            end[axis] = target[axis].unwrap_or(start[axis]);
+            delta[axis] = end[axis] - start[axis];
+        }
+
+        // The correction is followed by ending a piece on each line of the mesh crossed, and by
+        // cutting each span between them in short pieces if it isn't linear.
+        let mut cuts = ArrayVec::<[f32; MESH_POINTS_X + MESH_POINTS_Y + 1]>::new();
+        if self.leveling {
+            cuts.extend(mesh::crossings([start[0], start[1]], [end[0], end[1]]));
+            cuts.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        }
+        cuts.push(1.);
+        let curved = !KINEMATICS.is_linear()
+            || (self.leveling && matches!(MESH_INTERPOLATION, Interpolation::Bicubic));
+
+        // The feed rate applies to the tool's path, or to the extruder on its own.
+        let [dx, dy, dz, de] = delta;
+        let path = libm::sqrtf(dx * dx + dy * dy + dz * dz);
+        let length = if path == 0. { libm::fabsf(de) } else { path };
+        let speed = match move_type {
+            MoveType::Linear if self.feedrate > 0. => Some(self.feedrate / 60.),
+            _ => None,
+        };
+
+        let mut from = 0.;
+        for &to in &cuts {
+            let span = to - from;
+            let pieces = if curved {
+                (libm::ceilf(span * path / KINEMATIC_SEGMENT_LENGTH) as u32).max(1)
+            } else {
+                1
+            };
+            for n in 1..=pieces {
+                let ratio = from + span * n as f32 / pieces as f32;
+                let mut point = end;
+                if n < pieces || to < 1. {
+                    for axis in 0..AXIS_COUNT {
+                        point[axis] = start[axis] + delta[axis] * ratio;
+                    }
+                }
+                self.move_motors(point, length * span / pieces as f32, speed)
+                    .await;
+            }
+            from = to;
+        }
+        self.tool = end;
+    }
+
+    /// Plans a straight move of the motors to where they place the tool at `tool`.
+    async fn move_motors(&mut self, tool: [f32; AXIS_COUNT], length: f32, speed: Option<f32>) {
+        // The state refuses moves ending out of reach, only a piece of a move could be.
+        let target = match self.motors(tool) {
+            Some(target) => target,
+            None => return,
+        };
+        let mut steps = [0; AXIS_COUNT];
+        for (axis, s) in steps.iter_mut().enumerate() {
+            *s = target[axis] - self.position[axis];
+        }
+        if steps.iter().all(|s| *s == 0) {
+            return;
+        }
+
+        while self.planner.is_full() {
+            self.next_segment().await;
+        }
+        // Blocks too short to be planned are dropped, the motors stay where they are.
+        if self.planner.push(steps, length, speed) {
+            for (p, s) in self.position.iter_mut().zip(steps.iter()) {
+                *p += s;
+            }
+        }
+    }
+
+    /// Runs a drilling cycle along the axis normal to the plane, from the R level down to the
+    /// bottom of the hole at `hole`, then back up to the `retract` level.
+    async fn drill(&mut self, hole: [f32; 3], r: f32, retract: f32) {
+        let (_, _, normal) = arc::axes(self.plane);
+        let along = |level: f32| {
+            let mut target = [None; AXIS_COUNT];
+            target[normal] = Some(level);
+            target
+        };
+        let mut over = [None; AXIS_COUNT];
+        for axis in 0..3 {
+            if axis != normal {
+                over[axis] = Some(hole[axis]);
+            }
+        }
+
+        // Clear the work before moving over the hole.
+        if self.tool[normal] < r {
+            self.linear(MoveType::Quick, along(r)).await;
+        }
+        self.linear(MoveType::Quick, over).await;
+        self.linear(MoveType::Quick, along(r)).await;
+        self.linear(MoveType::Linear, along(hole[normal])).await;
+        self.linear(MoveType::Quick, along(retract)).await;
+    }
+
+    /// Runs a Bézier curve as a series of linear moves, the extruder moving in proportion to the
+    /// length travelled.
+    async fn bezier(&mut self, target: [Option<f32>; 3], control: [[f32; 2]; 2]) {
+        let [x, y, z, e] = self.tool;
+        let end = [target[0].unwrap_or(x), target[1].unwrap_or(y)];
+        let end_e = target[2].unwrap_or(e);
+        let curve = Bezier::new([x, y], end, control[0], control[1]);
+
+        let distance = |a: [f32; 2], b: [f32; 2]| libm::hypotf(b[0] - a[0], b[1] - a[1]);
+        let mut previous = [x, y];
+        let mut length = 0.;
+        for point in curve.flatten(BEZIER_TOLERANCE) {
+            length += distance(previous, point);
+            previous = point;
+        }
+
+        let mut previous = [x, y];
+        let mut travelled = 0.;
+        for point in curve.flatten(BEZIER_TOLERANCE) {
+            travelled += distance(previous, point);
+            previous = point;
+            let e = if length > 0. {
+                e + (end_e - e) * travelled / length
+            } else {
+                end_e
+            };
+            let target = [Some(point[0]), Some(point[1]), Some(z), Some(e)];
+            self.linear(MoveType::Linear, target).await;
+        }
+    }
+
+    /// Runs an arc as a series of linear moves, the extruder moving in proportion.
+    async fn arc(
+        &mut self,
+        direction: ArcDirection,
+        target: [Option<f32>; AXIS_COUNT],
+        offsets: [f32; 3],
+    ) {
+        let start = self.tool;
+        let mut end = start;
+        for axis in 0..AXIS_COUNT {
+            end[axis] = target[axis].unwrap_or(start[axis]);
+        }
+        let [x, y, z, e] = start;
+        let arc = Arc::new(
+            self.plane,
+            direction,
+            [x, y, z],
+            [end[0], end[1], end[2]],
+            offsets,
+        );
+
+        let count = arc.chords(ARC_TOLERANCE);
+        for n in 1..=count {
+            let [x, y, z] = arc.point(n, count);
+            let e = e + (end[3] - e) * n as f32 / count as f32;
+            self.linear(MoveType::Linear, [Some(x), Some(y), Some(z), Some(e)])
+                .await;
+        }
+    }
+}
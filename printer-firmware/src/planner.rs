@@ -0,0 +1,663 @@
+//! Motion planning.
+//!
+//! Moves are queued as [`Block`]s in a look-ahead buffer. A block is a straight line run along a
+//! speed profile: it accelerates from its entry speed up to its nominal speed, cruises, then
+//! decelerates down to the entry speed of the next block. The nominal speed and acceleration of a
+//! block are the requested ones, lowered so that no motor exceeds its own limits. Depending on
+//! [`RAMP_SHAPE`], the speed changes at a constant acceleration (a trapezoidal profile) or along
+//! jerk-limited S-curves, in which case the acceleration is continuous, and zero at the junctions.
+//!
+//! The entry speeds are planned again whenever a block is added, in two passes over the buffer:
+//! backwards, making sure that the machine can always come to a stop at the end of the buffer,
+//! then forwards, making sure that each entry speed can be reached from the previous one. The speed
+//! through a junction is further limited using the junction deviation: the corner is taken as if
+//! it were rounded by an arc passing [`JUNCTION_DEVIATION`] away from it, at the centripetal
+//! acceleration allowed by the blocks.
+//!
+//! The oldest block is cut into short [`Segment`]s, each run at a constant rate, when the step
+//! generator needs more. Its profile is settled when the first segment is cut, after which the
+//! entry speed of the next block cannot change anymore.
+
+use arrayvec::ArrayVec;
+
+use crate::config::{
+    RampShape, AXIS_COUNT, JUNCTION_DEVIATION, MAX_ACCELERATION, MAX_SPEED, RAMP_SHAPE,
+    STEPS_PER_MM,
+};
+use crate::stepper::{Segment, STEP_TIMER_HZ};
+
+/// Number of blocks the planner looks ahead.
+const PLANNER_DEPTH: usize = 16;
+
+/// Duration of the segments the blocks are cut into, in seconds.
+const SEGMENT_DURATION: f32 = 0.005;
+
+/// Iterations when searching for a speed, each one halving the uncertainty.
+const BISECTION_STEPS: usize = 20;
+
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    steps: [i32; AXIS_COUNT],
+    /// Length of the tool's path, or of the extruder's one on its own, in mm.
+    length: f32,
+    /// Direction of the move over all the motors, as a unit vector.
+    direction: [f32; AXIS_COUNT],
+    /// Cruise speed, in mm/s.
+    nominal_speed: f32,
+    /// in mm/s².
+    acceleration: f32,
+    /// Highest entry speed allowed by the junction with the previous block, in mm/s.
+    max_entry_speed: f32,
+    /// Planned entry speed, in mm/s.
+    entry_speed: f32,
+}
+
+impl Block {
+    /// `length` is the length of the tool's path, or of the extruder's one on its own, and `speed`
+    /// the requested speed in mm/s, `None` for as fast as the axes allow.
+    fn new(steps: [i32; AXIS_COUNT], length: f32, speed: Option<f32>) -> Option<Self> {
+        let mut delta = [0f32; AXIS_COUNT];
+        for axis in 0..AXIS_COUNT {
+            delta[axis] = steps[axis] as f32 / STEPS_PER_MM[axis];
+        }
+        if length <= 0. || steps.iter().all(|s| *s == 0) {
+            return None;
+        }
+        let norm = libm::sqrtf(delta.iter().map(|d| d * d).sum());
+
+        let mut direction = [0f32; AXIS_COUNT];
+        let mut nominal_speed = speed.unwrap_or(f32::INFINITY);
+        let mut acceleration = f32::INFINITY;
+        for axis in 0..AXIS_COUNT {
+            direction[axis] = delta[axis] / norm;
+            // Share of the path's speed and acceleration that this motor sees.
+            let ratio = libm::fabsf(delta[axis]) / length;
+            if ratio > 0. {
+                nominal_speed = nominal_speed.min(MAX_SPEED[axis] / ratio);
+                acceleration = acceleration.min(MAX_ACCELERATION[axis] / ratio);
+            }
+        }
+
+        Some(Self {
+            steps,
+            length,
+            direction,
+            nominal_speed,
+            acceleration,
+            max_entry_speed: 0.,
+            entry_speed: 0.,
+        })
+    }
+}
+
+/// Highest speed at which the corner between two blocks can be taken, in mm/s.
+fn junction_speed(previous: &Block, next: &Block) -> f32 {
+    // Cosine of the angle between the two blocks, -1 when they are aligned.
+    let cos_theta: f32 = -previous
+        .direction
+        .iter()
+        .zip(next.direction.iter())
+        .map(|(p, n)| p * n)
+        .sum::<f32>();
+    if cos_theta > 0.999_999 {
+        // The move reverses.
+        0.
+    } else if cos_theta < -0.999_999 {
+        f32::INFINITY
+    } else {
+        let sin_half_theta = libm::sqrtf(0.5 * (1. - cos_theta));
+        let acceleration = previous.acceleration.min(next.acceleration);
+        libm::sqrtf(acceleration * JUNCTION_DEVIATION * sin_half_theta / (1. - sin_half_theta))
+    }
+}
+
+/// A speed change starting and ending without acceleration.
+///
+/// With S-curve ramps the acceleration rises at a constant jerk, may stay at its limit for a while,
+/// then falls back to zero symmetrically. Trapezoidal ramps reach their acceleration at once.
+#[derive(Debug, Clone, Copy)]
+struct Ramp {
+    from: f32,
+    to: f32,
+    /// Highest acceleration of the ramp, negative when slowing down.
+    acceleration: f32,
+    /// Duration of each of the two phases during which the acceleration changes.
+    jerk_time: f32,
+    /// Duration of the constant acceleration phase.
+    acceleration_time: f32,
+}
+
+impl Ramp {
+    fn new(from: f32, to: f32, acceleration: f32) -> Self {
+        let change = libm::fabsf(to - from);
+        let (peak, jerk_time) = match RAMP_SHAPE {
+            RampShape::Trapezoidal => (acceleration, 0.),
+            // Small changes are over before the acceleration reaches its limit.
+            RampShape::SCurve { jerk } if change * jerk < acceleration * acceleration => {
+                let peak = libm::sqrtf(change * jerk);
+                (peak, peak / jerk)
+            }
+            RampShape::SCurve { jerk } => (acceleration, acceleration / jerk),
+        };
+        let acceleration_time = if change > 0. {
+            (change / peak - jerk_time).max(0.)
+        } else {
+            0.
+        };
+        Self {
+            from,
+            to,
+            acceleration: if to < from { -peak } else { peak },
+            jerk_time: if change > 0. { jerk_time } else { 0. },
+            acceleration_time,
+        }
+    }
+
+    fn duration(&self) -> f32 {
+        2. * self.jerk_time + self.acceleration_time
+    }
+
+    /// The ramp being symmetric, its mean speed is halfway between both ends.
+    fn distance(&self) -> f32 {
+        (self.from + self.to) / 2. * self.duration()
+    }
+
+    /// Distance covered `time` seconds into the ramp.
+    fn position(&self, time: f32) -> f32 {
+        let a = self.acceleration;
+        let (tj, ta) = (self.jerk_time, self.acceleration_time);
+        let jerk = if tj > 0. { a / tj } else { 0. };
+        if time <= tj {
+            self.from * time + jerk * time * time * time / 6.
+        } else if time <= tj + ta {
+            let t = time - tj;
+            let start = self.from * tj + jerk * tj * tj * tj / 6.;
+            let speed = self.from + a * tj / 2.;
+            start + speed * t + a * t * t / 2.
+        } else {
+            // The last phase mirrors the first one.
+            let t = (self.duration() - time).max(0.);
+            self.distance() - (self.to * t - jerk * t * t * t / 6.)
+        }
+    }
+}
+
+/// Highest speed that can be reached from `speed` over `distance`, or from which `speed` can be
+/// reached.
+fn reachable(speed: f32, acceleration: f32, distance: f32) -> f32 {
+    let trapezoidal = libm::sqrtf(speed * speed + 2. * acceleration * distance);
+    match RAMP_SHAPE {
+        RampShape::Trapezoidal => trapezoidal,
+        // S-curve ramps take longer, look for the largest one that fits.
+        RampShape::SCurve { .. } => {
+            let (mut low, mut high) = (speed, trapezoidal);
+            for _ in 0..BISECTION_STEPS {
+                let mid = (low + high) / 2.;
+                if Ramp::new(speed, mid, acceleration).distance() <= distance {
+                    low = mid;
+                } else {
+                    high = mid;
+                }
+            }
+            low
+        }
+    }
+}
+
+/// Speed profile of a block: accelerates from its entry speed, cruises, then decelerates to its
+/// exit speed.
+#[derive(Debug, Clone, Copy)]
+struct Profile {
+    accelerate: Ramp,
+    cruise_speed: f32,
+    cruise_time: f32,
+    decelerate: Ramp,
+}
+
+impl Profile {
+    fn new(block: &Block, exit_speed: f32) -> Self {
+        let (entry_speed, a) = (block.entry_speed, block.acceleration);
+        let ramps = |peak| {
+            (
+                Ramp::new(entry_speed, peak, a),
+                Ramp::new(peak, exit_speed, a),
+            )
+        };
+        let fits = |peak| {
+            let (up, down) = ramps(peak);
+            up.distance() + down.distance() <= block.length
+        };
+
+        // Unless the nominal speed is reached, look for where both ramps meet.
+        let mut peak = block.nominal_speed;
+        if !fits(peak) {
+            let (mut low, mut high) = (entry_speed.max(exit_speed), peak);
+            for _ in 0..BISECTION_STEPS {
+                let mid = (low + high) / 2.;
+                if fits(mid) {
+                    low = mid;
+                } else {
+                    high = mid;
+                }
+            }
+            peak = low;
+        }
+
+        let (accelerate, decelerate) = ramps(peak);
+        let cruise = (block.length - accelerate.distance() - decelerate.distance()).max(0.);
+        Self {
+            accelerate,
+            cruise_speed: peak,
+            cruise_time: if peak > 0. { cruise / peak } else { 0. },
+            decelerate,
+        }
+    }
+
+    fn duration(&self) -> f32 {
+        self.accelerate.duration() + self.cruise_time + self.decelerate.duration()
+    }
+
+    /// Distance covered `time` seconds into the block.
+    fn position(&self, time: f32) -> f32 {
+        let cruise_start = self.accelerate.duration();
+        let cruise_end = cruise_start + self.cruise_time;
+        if time <= cruise_start {
+            self.accelerate.position(time)
+        } else if time <= cruise_end {
+            self.accelerate.distance() + self.cruise_speed * (time - cruise_start)
+        } else {
+            self.accelerate.distance()
+                + self.cruise_speed * self.cruise_time
+                + self.decelerate.position(time - cruise_end)
+        }
+    }
+}
+
+/// The block being cut into segments.
+struct Current {
+    block: Block,
+    profile: Profile,
+    /// Time elapsed since the start of the block, in seconds.
+    time: f32,
+    /// Steps performed so far.
+    steps: [i32; AXIS_COUNT],
+}
+
+pub struct Planner {
+    blocks: ArrayVec<[Block; PLANNER_DEPTH]>,
+    current: Option<Current>,
+    /// Exit speed of the block cut into segments last, in mm/s.
+    exit_speed: f32,
+    /// The last block added, for the junction with the next one.
+    last: Option<Block>,
+}
+
+impl Planner {
+    pub fn new() -> Self {
+        Self {
+            blocks: ArrayVec::new(),
+            current: None,
+            exit_speed: 0.,
+            last: None,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.blocks.is_full()
+    }
+
+    /// Whether every planned block was cut into segments.
+    pub fn is_empty(&self) -> bool {
+        self.current.is_none() && self.blocks.is_empty()
+    }
+
+    /// Plans a move of `steps` along a path of `length` mm, at `speed` mm/s, `None` meaning as fast
+    /// as possible.
+    ///
+    /// Returns whether the move was planned: moves without any step or of a null length are
+    /// dropped. Must not be called while the planner is full.
+    pub fn push(&mut self, steps: [i32; AXIS_COUNT], length: f32, speed: Option<f32>) -> bool {
+        let mut block = match Block::new(steps, length, speed) {
+            Some(block) => block,
+            None => return false,
+        };
+        if let Some(previous) = &self.last {
+            block.max_entry_speed = junction_speed(previous, &block)
+                .min(previous.nominal_speed)
+                .min(block.nominal_speed);
+        }
+        self.last = Some(block);
+        self.blocks
+            .try_push(block)
+            .unwrap_or_else(|_| unreachable!());
+        self.plan();
+        true
+    }
+
+    fn plan(&mut self) {
+        // Every block must be able to stop by the end of the buffer.
+        let mut exit_speed = 0.;
+        for block in self.blocks.iter_mut().rev() {
+            block.entry_speed =
+                block
+                    .max_entry_speed
+                    .min(reachable(exit_speed, block.acceleration, block.length));
+            exit_speed = block.entry_speed;
+        }
+
+        // Every entry speed must be reachable from the motion already cut into segments, whose
+        // profile is settled.
+        let mut limit = self.exit_speed;
+        for block in self.blocks.iter_mut() {
+            block.entry_speed = block.entry_speed.min(limit);
+            limit = reachable(block.entry_speed, block.acceleration, block.length);
+        }
+    }
+
+    /// Cuts the next segment out of the planned blocks.
+    ///
+    /// The profile of a block is settled once it starts being cut into segments.
+    pub fn next_segment(&mut self) -> Option<Segment> {
+        if self.current.is_none() {
+            if self.blocks.is_empty() {
+                return None;
+            }
+            let block = self.blocks.remove(0);
+            self.exit_speed = self.blocks.first().map_or(0., |next| next.entry_speed);
+            self.current = Some(Current {
+                block,
+                profile: Profile::new(&block, self.exit_speed),
+                time: 0.,
+                steps: [0; AXIS_COUNT],
+            });
+        }
+        let current = self.current.as_mut().unwrap_or_else(|| unreachable!());
+        let block = &current.block;
+
+        let end = (current.time + SEGMENT_DURATION).min(current.profile.duration());
+        let done = end >= current.profile.duration();
+        let distance = current.profile.position(end).min(block.length);
+
+        let mut steps = [0; AXIS_COUNT];
+        for ((s, performed), total) in steps
+            .iter_mut()
+            .zip(current.steps.iter_mut())
+            .zip(block.steps.iter())
+        {
+            let target = if done {
+                *total
+            } else {
+                libm::roundf(*total as f32 * distance / block.length) as i32
+            };
+            *s = target - *performed;
+            *performed = target;
+        }
+        let duration = end - current.time;
+        current.time = end;
+
+        if done {
+            self.current = None;
+        }
+        Some(Segment::linear(
+            steps,
+            (duration * STEP_TIMER_HZ as f32) as u32,
+        ))
+    }
+}
+
+impl Default for Planner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that `value` is within 0.1% of the `reference`.
+    fn assert_close(value: f32, reference: f32) {
+        assert!(
+            libm::fabsf(value - reference) <= 1e-3 * libm::fabsf(reference),
+            "{} is not close to {}",
+            value,
+            reference
+        );
+    }
+
+    /// Speed `time` seconds into a move whose covered distance is given by `position`.
+    fn speed(position: impl Fn(f32) -> f32, time: f32) -> f32 {
+        let dt = 1e-4;
+        (position(time + dt) - position(time - dt)) / (2. * dt)
+    }
+
+    fn jerk() -> f32 {
+        match RAMP_SHAPE {
+            RampShape::SCurve { jerk } => jerk,
+            RampShape::Trapezoidal => f32::INFINITY,
+        }
+    }
+
+    #[test]
+    fn block_limits() {
+        // 10mm along X and Z: Z is 80 times slower and 30 times less nimble than X.
+        let block = Block::new([800, 0, 4000, 0], libm::sqrtf(200.), Some(100.)).unwrap();
+        let ratio = 10. / libm::sqrtf(200.);
+        assert_close(block.nominal_speed, MAX_SPEED[2] / ratio);
+        assert_close(block.acceleration, MAX_ACCELERATION[2] / ratio);
+        assert_close(block.direction[0], ratio);
+        assert_close(block.direction[2], ratio);
+
+        let block = Block::new([800, 0, 0, 0], 10., Some(100.)).unwrap();
+        assert_eq!(block.nominal_speed, 100.);
+        assert_eq!(block.acceleration, MAX_ACCELERATION[0]);
+
+        assert!(Block::new([0; AXIS_COUNT], 10., None).is_none());
+        assert!(Block::new([800, 0, 0, 0], 0., None).is_none());
+    }
+
+    #[test]
+    fn ramp() {
+        let (a, j) = (MAX_ACCELERATION[0], jerk());
+        // Long enough for the acceleration to reach its limit.
+        let ramp = Ramp::new(0., 100., a);
+        let tj = (a / j).min(libm::sqrtf(100. / j));
+        let ta = (100. / a - tj).max(0.);
+        assert_close(ramp.duration(), 2. * tj + ta);
+        assert_close(ramp.distance(), 50. * ramp.duration());
+        assert_close(ramp.position(ramp.duration()), ramp.distance());
+        assert_eq!(ramp.position(0.), 0.);
+        assert_close(speed(|t| ramp.position(t), tj), a * tj / 2.);
+        assert_close(speed(|t| ramp.position(t), tj + ta / 2.), 50.);
+
+        // Slowing down mirrors speeding up.
+        let down = Ramp::new(100., 0., a);
+        assert_close(down.duration(), ramp.duration());
+        assert_close(down.distance(), ramp.distance());
+        let t = ramp.duration() / 3.;
+        assert_close(
+            down.position(t),
+            ramp.distance() - ramp.position(ramp.duration() - t),
+        );
+
+        let flat = Ramp::new(50., 50., a);
+        assert_eq!(flat.duration(), 0.);
+        assert_eq!(flat.distance(), 0.);
+    }
+
+    /// Checks that the speed and, with S-curve ramps, the acceleration of a move whose covered
+    /// distance is given by `position` don't jump at `time`.
+    fn assert_continuous(position: impl Fn(f32) -> f32, time: f32, max_acceleration: f32) {
+        // Small enough steps for the speed to barely change over them, large enough ones for the
+        // acceleration to stand out from the rounding errors.
+        let (h, k) = (1e-5, 1e-3);
+        let rounding = 8. * f32::EPSILON * libm::fabsf(position(time));
+        let before = (position(time) - position(time - h)) / h;
+        let after = (position(time + h) - position(time)) / h;
+        assert!(
+            libm::fabsf(after - before) <= 2. * max_acceleration * h + rounding / h,
+            "speed jumps from {} to {} at {}",
+            before,
+            after,
+            time
+        );
+        if let RampShape::SCurve { jerk } = RAMP_SHAPE {
+            let second = |t: f32| (position(t + k) - 2. * position(t) + position(t - k)) / k / k;
+            let (before, after) = (second(time - k), second(time + k));
+            assert!(
+                libm::fabsf(after - before) <= 3. * jerk * k + 2. * rounding / k / k,
+                "acceleration jumps from {} to {} at {}",
+                before,
+                after,
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn continuous_ramps() {
+        let a = MAX_ACCELERATION[0];
+        // With and without a constant acceleration phase.
+        for (from, to) in &[(0., 100.), (100., 10.), (0., 4.), (20., 18.)] {
+            let ramp = Ramp::new(*from, *to, a);
+            let (tj, ta) = (ramp.jerk_time, ramp.acceleration_time);
+            for time in &[tj, tj + ta] {
+                assert_continuous(|t| ramp.position(t), *time, a);
+            }
+        }
+    }
+
+    #[test]
+    fn continuous_profiles() {
+        let mut block = Block::new([8000, 0, 0, 0], 100., Some(100.)).unwrap();
+        block.entry_speed = 20.;
+        let a = block.acceleration;
+        let profile = Profile::new(&block, 10.);
+        let (up, down) = (&profile.accelerate, &profile.decelerate);
+        let cruise_end = up.duration() + profile.cruise_time;
+        for time in &[
+            up.jerk_time,
+            up.jerk_time + up.acceleration_time,
+            up.duration(),
+            cruise_end,
+            cruise_end + down.jerk_time,
+            cruise_end + down.jerk_time + down.acceleration_time,
+        ] {
+            assert_continuous(|t| profile.position(t), *time, a);
+        }
+
+        // Without cruise, the acceleration ramps straight into the deceleration.
+        let block = Block::new([80, 0, 0, 0], 1., Some(100.)).unwrap();
+        let profile = Profile::new(&block, 0.);
+        assert_continuous(
+            |t| profile.position(t),
+            profile.accelerate.duration(),
+            block.acceleration,
+        );
+    }
+
+    #[test]
+    fn cruising_profile() {
+        let mut block = Block::new([8000, 0, 0, 0], 100., Some(100.)).unwrap();
+        block.entry_speed = 20.;
+        let profile = Profile::new(&block, 10.);
+        let (up, down) = (
+            Ramp::new(20., 100., block.acceleration),
+            Ramp::new(100., 10., block.acceleration),
+        );
+        assert_eq!(profile.cruise_speed, 100.);
+        assert_close(
+            profile.cruise_time,
+            (100. - up.distance() - down.distance()) / 100.,
+        );
+        assert_close(
+            profile.duration(),
+            up.duration() + profile.cruise_time + down.duration(),
+        );
+        assert_close(profile.position(profile.duration()), 100.);
+
+        // Speed gained or lost `t` seconds into a ramp, while the acceleration changes.
+        let a = block.acceleration;
+        let gain = |t: f32| match RAMP_SHAPE {
+            RampShape::SCurve { jerk } => jerk * t * t / 2.,
+            RampShape::Trapezoidal => a * t,
+        };
+        let t = 1e-3;
+        assert_close(speed(|t| profile.position(t), t), 20. + gain(t));
+        assert_close(
+            speed(|t| profile.position(t), profile.duration() / 2.),
+            100.,
+        );
+        let down = &profile.decelerate;
+        assert_close(
+            speed(|t| down.position(t), down.duration() - t),
+            10. + gain(t),
+        );
+    }
+
+    #[test]
+    fn short_profile() {
+        // Too short to reach the nominal speed: both ramps meet halfway.
+        let block = Block::new([80, 0, 0, 0], 1., Some(100.)).unwrap();
+        let profile = Profile::new(&block, 0.);
+        assert!(profile.cruise_speed < 100.);
+        assert!(profile.cruise_time < 1e-3);
+        assert_close(profile.accelerate.distance(), 0.5);
+        assert_close(profile.decelerate.distance(), 0.5);
+        assert_close(reachable(0., block.acceleration, 0.5), profile.cruise_speed);
+    }
+
+    #[test]
+    fn junctions() {
+        let mut planner = Planner::new();
+        assert!(planner.push([800, 0, 0, 0], 10., Some(100.)));
+        assert!(planner.push([800, 0, 0, 0], 10., Some(100.)));
+        // Straight through at full speed.
+        assert_eq!(planner.blocks[0].entry_speed, 0.);
+        assert_eq!(planner.blocks[1].entry_speed, 100.);
+
+        // A right angle is taken as an arc passing the junction deviation away from the corner.
+        assert!(planner.push([0, 800, 0, 0], 10., Some(100.)));
+        let sin_half_theta = libm::sqrtf(0.5);
+        let reference = libm::sqrtf(
+            MAX_ACCELERATION[0] * JUNCTION_DEVIATION * sin_half_theta / (1. - sin_half_theta),
+        );
+        assert_close(planner.blocks[2].entry_speed, reference);
+
+        // Going back stops.
+        assert!(planner.push([0, -800, 0, 0], 10., Some(100.)));
+        assert_eq!(planner.blocks[3].entry_speed, 0.);
+
+        assert!(!planner.push([0; AXIS_COUNT], 10., Some(100.)));
+        assert!(!planner.push([800, 0, 0, 0], 0., Some(100.)));
+        assert_eq!(planner.blocks.len(), 4);
+    }
+
+    #[test]
+    fn segments() {
+        let mut planner = Planner::new();
+        assert!(planner.push(
+            [8000, -4000, 0, 0],
+            libm::sqrtf(100. * 100. + 50. * 50.),
+            None
+        ));
+        let duration = Profile::new(&planner.blocks[0], 0.).duration();
+
+        let mut total = [0; AXIS_COUNT];
+        let mut count = 0;
+        while let Some(segment) = planner.next_segment() {
+            match segment {
+                Segment::Move { steps, .. } => {
+                    for (t, s) in total.iter_mut().zip(steps.iter()) {
+                        *t += s;
+                    }
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+            count += 1;
+        }
+        assert_eq!(total, [8000, -4000, 0, 0]);
+        assert_eq!(count, libm::ceilf(duration / SEGMENT_DURATION) as usize);
+        assert!(planner.is_empty());
+    }
+}
@@ -1,19 +1,91 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
 use stm32l4xx_hal::{
+    gpio::{
+        gpioa::{PA15, PA2, PA3, PA4, PA5, PA6, PA7},
+        gpiob::{PB0, PB1, PB2, PB4},
+        gpioc::PC0,
+        gpiod::PD14,
+        Input, Output, PullUp, PushPull,
+    },
     prelude::*,
-    serial::{self, Rx, Serial, Tx},
-    stm32::{Peripherals, USART1},
+    rcc::Clocks,
+    serial::{self, Event, Rx, Serial, Tx},
+    stm32::{interrupt, Interrupt, Peripherals, RCC, TIM2, USART1},
 };
 
-pub(crate) struct Platform {
-    pub sout: Tx<USART1>,
-    pub sin: Rx<USART1>,
-    pub name: &'static str,
+use super::Platform;
+use crate::ring_buffer::Stats;
+use crate::serial::{RxBuffer, RxStream, TxBuffer, TxSink};
+use crate::stepper::{SegmentQueue, SegmentSink, StepGenerator, StepperPins, STEP_TIMER_HZ};
+
+static SERIAL: Mutex<RefCell<Option<(Rx<USART1>, Tx<USART1>)>>> = Mutex::new(RefCell::new(None));
+static RX_BUFFER: RxBuffer = RxBuffer::new();
+static TX_BUFFER: TxBuffer = TxBuffer::new();
+
+static SEGMENTS: SegmentQueue = SegmentQueue::new();
+
+// Wired as on a CNC shield v3 plugged in the Arduino headers, the extruder being on the A slot
+// (D12/D13).
+shield_stepping! {
+    x_step: PD14<Output<PushPull>>, // D2
+    y_step: PB0<Output<PushPull>>,  // D3
+    z_step: PA3<Output<PushPull>>,  // D4
+    e_step: PA6<Output<PushPull>>,  // D12
+    x_dir: PB4<Output<PushPull>>,   // D5
+    y_dir: PB1<Output<PushPull>>,   // D6
+    z_dir: PA4<Output<PushPull>>,   // D7
+    e_dir: PA5<Output<PushPull>>,   // D13
+    enable: PB2<Output<PushPull>>,  // D8
+    x_endstop: PA15<Input<PullUp>>, // D9
+    y_endstop: PA2<Input<PullUp>>,  // D10
+    z_endstop: PA7<Input<PullUp>>,  // D11
+    probe: PC0<Input<PullUp>>,      // A5
+}
+
+impl From<serial::Error> for crate::serial::Error {
+    fn from(e: serial::Error) -> Self {
+        match e {
+            serial::Error::Overrun => Self::Overrun,
+            serial::Error::Framing => Self::Framing,
+            serial::Error::Parity => Self::Parity,
+            _ => Self::Noise,
+        }
+    }
+}
+
+fn start_transmission() {
+    // SAFETY: CR1 is otherwise only modified by the interrupt handler.
+    cortex_m::interrupt::free(|_| unsafe {
+        (*USART1::ptr()).cr1.modify(|_, w| w.txeie().set_bit())
+    });
+}
+
+#[interrupt]
+fn USART1() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some((rx, tx)) = SERIAL.borrow(cs).borrow_mut().as_mut() {
+            crate::serial::receive(rx, &RX_BUFFER);
+            if crate::serial::transmit(tx, &TX_BUFFER) {
+                // SAFETY: interrupts are masked, nothing else can be modifying CR1.
+                unsafe { (*USART1::ptr()).cr1.modify(|_, w| w.txeie().clear_bit()) };
+            }
+        }
+    });
+}
+
+pub(crate) struct DiscoL475 {
+    serial: Option<(RxStream, TxSink)>,
+    steppers: Option<SegmentSink>,
+    clocks: Clocks,
 }
 
-impl Platform {
+impl DiscoL475 {
     pub fn take() -> Self {
         // Get access to the device specific peripherals from the peripheral access crate
         let p = Peripherals::take().unwrap_or_else(|| unreachable!());
+        let cp = cortex_m::Peripherals::take().unwrap_or_else(|| unreachable!());
 
         // Take ownership over the raw flash and rcc devices and convert them
         // into the corresponding HAL structs
@@ -25,25 +97,115 @@ impl Platform {
         // the frozen frequencies in `clocks`
         let clocks = rcc.cfgr.sysclk(80.mhz()).freeze(&mut flash.acr, &mut pwr);
 
-        // Acquire the GPIOC peripheral
-        let mut gpioa = p.GPIOB.split(&mut rcc.ahb2);
+        // Acquire the GPIO peripherals
+        let mut gpioa = p.GPIOA.split(&mut rcc.ahb2);
+        let mut gpiob = p.GPIOB.split(&mut rcc.ahb2);
+        let mut gpioc = p.GPIOC.split(&mut rcc.ahb2);
+        let mut gpiod = p.GPIOD.split(&mut rcc.ahb2);
 
-        let tx = gpioa.pb6.into_af7(&mut gpioa.moder, &mut gpioa.afrl);
-        let rx = gpioa.pb7.into_af7(&mut gpioa.moder, &mut gpioa.afrl);
+        let tx = gpiob.pb6.into_af7(&mut gpiob.moder, &mut gpiob.afrl);
+        let rx = gpiob.pb7.into_af7(&mut gpiob.moder, &mut gpiob.afrl);
 
-        let (tx, rx) = Serial::usart1(
+        let mut serial = Serial::usart1(
             p.USART1,
             (tx, rx),
             serial::Config::default().baudrate(115_200.bps()),
             clocks,
             &mut rcc.apb2,
-        )
-        .split();
+        );
+        serial.listen(Event::Rxne);
+        let (tx, rx) = serial.split();
+
+        cortex_m::interrupt::free(|cs| SERIAL.borrow(cs).replace(Some((rx, tx))));
+        // SAFETY: the handler only touches SERIAL and the buffers which are ready to be used.
+        unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::USART1) };
+
+        let mut pins = Pins {
+            x_step: gpiod
+                .pd14
+                .into_push_pull_output(&mut gpiod.moder, &mut gpiod.otyper),
+            y_step: gpiob
+                .pb0
+                .into_push_pull_output(&mut gpiob.moder, &mut gpiob.otyper),
+            z_step: gpioa
+                .pa3
+                .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper),
+            e_step: gpioa
+                .pa6
+                .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper),
+            x_dir: gpiob
+                .pb4
+                .into_push_pull_output(&mut gpiob.moder, &mut gpiob.otyper),
+            y_dir: gpiob
+                .pb1
+                .into_push_pull_output(&mut gpiob.moder, &mut gpiob.otyper),
+            z_dir: gpioa
+                .pa4
+                .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper),
+            e_dir: gpioa
+                .pa5
+                .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper),
+            enable: gpiob
+                .pb2
+                .into_push_pull_output(&mut gpiob.moder, &mut gpiob.otyper),
+            x_endstop: gpioa
+                .pa15
+                .into_pull_up_input(&mut gpioa.moder, &mut gpioa.pupdr),
+            y_endstop: gpioa
+                .pa2
+                .into_pull_up_input(&mut gpioa.moder, &mut gpioa.pupdr),
+            z_endstop: gpioa
+                .pa7
+                .into_pull_up_input(&mut gpioa.moder, &mut gpioa.pupdr),
+            probe: gpioc
+                .pc0
+                .into_pull_up_input(&mut gpioc.moder, &mut gpioc.pupdr),
+        };
+        pins.set_enabled(false);
+        let steppers = StepGenerator::new(pins, &SEGMENTS);
+        cortex_m::interrupt::free(|cs| STEPPERS.borrow(cs).replace(Some(steppers)));
+
+        // TIM2 counts at STEP_TIMER_HZ and interrupts at the end of each step interval. Its clock
+        // runs twice as fast as APB1 when the latter is divided.
+        let timclk = clocks.pclk1().0 * if clocks.ppre1() == 1 { 1 } else { 2 };
+        // SAFETY: the HAL leaves APB1ENR1's TIM2EN alone.
+        unsafe { (*RCC::ptr()).apb1enr1.modify(|_, w| w.tim2en().set_bit()) };
+        let tim = p.TIM2;
+        tim.psc
+            .write(|w| unsafe { w.bits(timclk / STEP_TIMER_HZ - 1) });
+        // Load the prescaler right away.
+        tim.egr.write(|w| w.ug().set_bit());
+        tim.sr.modify(|_, w| w.uif().clear_bit());
+        tim.dier.write(|w| w.uie().set_bit());
+        // SAFETY: the handler only touches TIM2 and STEPPERS which are ready to be used.
+        unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::TIM2) };
+
+        super::start_systick(cp.SYST, clocks.sysclk().0);
 
         Self {
-            sin: rx,
-            sout: tx,
-            name: "disco-l475-iot01a",
+            serial: Some((RX_BUFFER.stream(), TX_BUFFER.sink(start_transmission))),
+            steppers: Some(SEGMENTS.sink(start_stepping)),
+            clocks,
         }
     }
 }
+
+impl Platform for DiscoL475 {
+    type SerialIn = RxStream;
+
+    fn name(&self) -> &'static str {
+        "disco-l475-iot01a"
+    }
+    fn sysclk(&self) -> u32 {
+        self.clocks.sysclk().0
+    }
+    fn serial(&mut self) -> (Self::SerialIn, TxSink) {
+        self.serial.take().unwrap_or_else(|| unreachable!())
+    }
+    fn rx_stats(&self) -> Stats {
+        RX_BUFFER.stats()
+    }
+    fn steppers(&mut self) -> SegmentSink {
+        self.steppers.take().unwrap_or_else(|| unreachable!())
+    }
+}
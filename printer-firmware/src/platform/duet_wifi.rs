@@ -0,0 +1,327 @@
+//! Duet WiFi (ATSAM4E8E).
+//!
+//! There is no HAL for the SAM4E in our dependencies so the few peripherals we need are driven
+//! directly through their registers. G-code is exchanged over UART0 which is wired to the PanelDue
+//! connector (URXD0: PA9, UTXD0: PA10). USB and the WiFi module are not supported yet.
+//!
+//! The TMC2660 stepper drivers are not supported yet either, the step generator still runs (from
+//! TC0) so that the motion timing is honoured. Neither are the endstops and the probe, which homing
+//! and probing never find.
+//!
+//! Without a device crate there is no vector table entry to bind to the peripherals' interrupts,
+//! they are dispatched from the `DefaultHandler` instead.
+
+use core::cell::RefCell;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cortex_m::interrupt::Mutex;
+
+use super::Platform;
+use crate::ring_buffer::Stats;
+use crate::serial::{Error as SerialError, RxBuffer, RxStream, TxBuffer, TxSink};
+use crate::stepper::{SegmentQueue, SegmentSink, StepGenerator, MIN_STEP_INTERVAL, STEP_TIMER_HZ};
+
+/// Frequency of the crystal fitted on the board.
+const MAINCK: u32 = 12_000_000;
+/// PLLA multiplier: 12MHz * 10 = 120MHz.
+const PLLA_MUL: u32 = 10;
+const MCK: u32 = MAINCK * PLLA_MUL;
+const BAUDRATE: u32 = 115_200;
+
+const PMC: usize = 0x400E_0400;
+const PMC_PCER0: usize = PMC + 0x10;
+const CKGR_MOR: usize = PMC + 0x20;
+const CKGR_PLLAR: usize = PMC + 0x28;
+const PMC_MCKR: usize = PMC + 0x30;
+const PMC_SR: usize = PMC + 0x68;
+
+const EEFC_FMR: usize = 0x400E_0A00;
+const WDT_MR: usize = 0x400E_1854;
+
+const PIOA: usize = 0x400E_0E00;
+const PIO_PDR: usize = PIOA + 0x04;
+const PIO_ABCDSR1: usize = PIOA + 0x70;
+const PIO_ABCDSR2: usize = PIOA + 0x74;
+
+const UART0: usize = 0x400E_0600;
+const UART_CR: usize = UART0;
+const UART_MR: usize = UART0 + 0x04;
+const UART_IER: usize = UART0 + 0x08;
+const UART_IDR: usize = UART0 + 0x0C;
+const UART_SR: usize = UART0 + 0x14;
+const UART_RHR: usize = UART0 + 0x18;
+const UART_THR: usize = UART0 + 0x1C;
+const UART_BRGR: usize = UART0 + 0x20;
+
+const TC0: usize = 0x4009_0000;
+const TC_CCR: usize = TC0;
+const TC_CMR: usize = TC0 + 0x04;
+const TC_RC: usize = TC0 + 0x1C;
+const TC_SR: usize = TC0 + 0x20;
+const TC_IER: usize = TC0 + 0x24;
+
+const ID_UART0: u32 = 7;
+const ID_TC0: u32 = 21;
+const URXD0: u32 = 1 << 9;
+const UTXD0: u32 = 1 << 10;
+
+const UART_SR_RXRDY: u32 = 1 << 0;
+const UART_SR_TXRDY: u32 = 1 << 1;
+const UART_SR_OVRE: u32 = 1 << 5;
+const UART_SR_FRAME: u32 = 1 << 6;
+const UART_SR_PARE: u32 = 1 << 7;
+const UART_CR_RSTSTA: u32 = 1 << 8;
+
+const TC_CCR_CLKEN: u32 = 1 << 0;
+const TC_CCR_CLKDIS: u32 = 1 << 1;
+const TC_CCR_SWTRG: u32 = 1 << 2;
+const TC_CPCS: u32 = 1 << 4;
+
+/// TC0 runs from MCK/8, a whole number of times per step timer tick.
+const TC_CLOCKS_PER_TICK: u32 = MCK / 8 / STEP_TIMER_HZ;
+/// TC0 is only 16 bits wide, longer delays are split.
+const TC_MAX_PERIOD: u64 = 0xFFFF;
+
+const NVIC_ISER0: usize = 0xE000_E100;
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static RX_BUFFER: RxBuffer = RxBuffer::new();
+static TX_BUFFER: TxBuffer = TxBuffer::new();
+
+/// The step generator along with the number of TC0 clocks left until its next step event.
+static STEPPERS: Mutex<RefCell<Option<(StepGenerator<()>, u64)>>> = Mutex::new(RefCell::new(None));
+static SEGMENTS: SegmentQueue = SegmentQueue::new();
+static STEPPING: AtomicBool = AtomicBool::new(false);
+
+unsafe fn read(reg: usize) -> u32 {
+    read_volatile(reg as *const u32)
+}
+unsafe fn write(reg: usize, value: u32) {
+    write_volatile(reg as *mut u32, value)
+}
+unsafe fn wait_for(reg: usize, mask: u32) {
+    while read(reg) & mask == 0 {}
+}
+
+/// UART0's receive side, only ever used from its interrupt handler.
+struct Uart0Rx;
+
+impl embedded_hal::serial::Read<u8> for Uart0Rx {
+    type Error = SerialError;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        // SAFETY: UART0's receive side is only ever accessed from its interrupt handler.
+        unsafe {
+            let sr = read(UART_SR);
+            let err = if sr & UART_SR_OVRE != 0 {
+                Some(SerialError::Overrun)
+            } else if sr & UART_SR_FRAME != 0 {
+                Some(SerialError::Framing)
+            } else if sr & UART_SR_PARE != 0 {
+                Some(SerialError::Parity)
+            } else {
+                None
+            };
+            if let Some(err) = err {
+                write(UART_CR, UART_CR_RSTSTA);
+                Err(nb::Error::Other(err))
+            } else if sr & UART_SR_RXRDY != 0 {
+                Ok(read(UART_RHR) as u8)
+            } else {
+                Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+}
+
+/// UART0's transmit side, only ever used from its interrupt handler.
+struct Uart0Tx;
+
+impl embedded_hal::serial::Write<u8> for Uart0Tx {
+    type Error = core::convert::Infallible;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        // SAFETY: UART0's transmit side is only ever accessed from its interrupt handler.
+        unsafe {
+            if read(UART_SR) & UART_SR_TXRDY == 0 {
+                return Err(nb::Error::WouldBlock);
+            }
+            write(UART_THR, u32::from(byte));
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+fn start_transmission() {
+    // SAFETY: writing IER only sets the given bit, it can't race with the handler.
+    unsafe { write(UART_IER, UART_SR_TXRDY) };
+}
+
+fn uart0_interrupt() {
+    crate::serial::receive(&mut Uart0Rx, &RX_BUFFER);
+    if crate::serial::transmit(&mut Uart0Tx, &TX_BUFFER) {
+        // SAFETY: writing IDR only clears the given bit.
+        unsafe { write(UART_IDR, UART_SR_TXRDY) };
+    }
+}
+
+fn start_stepping() {
+    cortex_m::interrupt::free(|_| {
+        if !STEPPING.swap(true, Ordering::AcqRel) {
+            // SAFETY: TC0 is otherwise only used by its interrupt handler, which is masked.
+            unsafe {
+                write(TC_RC, MIN_STEP_INTERVAL * TC_CLOCKS_PER_TICK);
+                write(TC_CCR, TC_CCR_CLKEN | TC_CCR_SWTRG);
+            }
+        }
+    });
+}
+
+fn tc0_interrupt() {
+    cortex_m::interrupt::free(|cs| {
+        // SAFETY: TC0 is only used by this handler and `start_stepping` while it is masked.
+        unsafe {
+            // Acknowledge the RC compare.
+            read(TC_SR);
+            if let Some((steppers, left)) = STEPPERS.borrow(cs).borrow_mut().as_mut() {
+                if *left == 0 {
+                    match steppers.tick() {
+                        Some(interval) => {
+                            *left = u64::from(interval) * u64::from(TC_CLOCKS_PER_TICK)
+                        }
+                        None => {
+                            write(TC_CCR, TC_CCR_CLKDIS);
+                            STEPPING.store(false, Ordering::Release);
+                            return;
+                        }
+                    }
+                }
+                // The counter was reset on compare, the new period applies to the one that just
+                // started.
+                let period = (*left).min(TC_MAX_PERIOD);
+                write(TC_RC, period as u32);
+                *left -= period;
+            }
+        }
+    });
+}
+
+#[cortex_m_rt::exception]
+fn DefaultHandler(irqn: i16) {
+    match irqn as u32 {
+        ID_UART0 => uart0_interrupt(),
+        ID_TC0 => tc0_interrupt(),
+        _ => {}
+    }
+}
+
+pub(crate) struct DuetWifi {
+    serial: Option<(RxStream, TxSink)>,
+    steppers: Option<SegmentSink>,
+}
+
+impl DuetWifi {
+    pub fn take() -> Self {
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            unreachable!()
+        }
+
+        // SAFETY: this runs once, before anything else touches the peripherals.
+        unsafe {
+            // The watchdog is enabled out of reset and would bite after ~16s.
+            write(WDT_MR, 1 << 15);
+
+            // 5 wait states are required to run the flash at 120MHz.
+            write(EEFC_FMR, 5 << 8);
+
+            // Start the crystal oscillator and switch the main clock over to it.
+            const KEY: u32 = 0x37 << 16;
+            const MOSCXTEN: u32 = 1 << 0;
+            const MOSCRCEN: u32 = 1 << 3;
+            const MOSCXTST: u32 = 0xFF << 8;
+            const MOSCSEL: u32 = 1 << 24;
+            write(CKGR_MOR, KEY | MOSCXTST | MOSCRCEN | MOSCXTEN);
+            wait_for(PMC_SR, 1 << 0); // MOSCXTS
+            write(CKGR_MOR, KEY | MOSCXTST | MOSCRCEN | MOSCXTEN | MOSCSEL);
+            wait_for(PMC_SR, 1 << 16); // MOSCSELS
+
+            // PLLA = MAINCK * PLLA_MUL / 1
+            const ONE: u32 = 1 << 29;
+            const PLLACOUNT: u32 = 0x3F << 8;
+            write(CKGR_PLLAR, ONE | ((PLLA_MUL - 1) << 16) | PLLACOUNT | 1);
+            wait_for(PMC_SR, 1 << 1); // LOCKA
+
+            // Switch MCK to PLLA, the prescaler must be written first.
+            write(PMC_MCKR, read(PMC_MCKR) & !(0x7 << 4));
+            wait_for(PMC_SR, 1 << 3); // MCKRDY
+            write(PMC_MCKR, (read(PMC_MCKR) & !0x3) | 2);
+            wait_for(PMC_SR, 1 << 3); // MCKRDY
+
+            // Hand PA9/PA10 over to UART0 (peripheral A) and configure it for 8N1 at BAUDRATE.
+            write(PMC_PCER0, 1 << ID_UART0);
+            write(PIO_ABCDSR1, read(PIO_ABCDSR1) & !(URXD0 | UTXD0));
+            write(PIO_ABCDSR2, read(PIO_ABCDSR2) & !(URXD0 | UTXD0));
+            write(PIO_PDR, URXD0 | UTXD0);
+
+            const RSTRX: u32 = 1 << 2;
+            const RSTTX: u32 = 1 << 3;
+            const RXEN: u32 = 1 << 4;
+            const TXEN: u32 = 1 << 6;
+            const PAR_NO: u32 = 4 << 9;
+            write(UART_CR, RSTRX | RSTTX | UART_CR_RSTSTA);
+            write(UART_MR, PAR_NO);
+            write(UART_BRGR, MCK / (16 * BAUDRATE));
+            write(UART_CR, RXEN | TXEN);
+
+            write(
+                UART_IER,
+                UART_SR_RXRDY | UART_SR_OVRE | UART_SR_FRAME | UART_SR_PARE,
+            );
+            write(NVIC_ISER0, 1 << ID_UART0);
+
+            // TC0 channel 0 in waveform mode, counting up to RC from MCK/8.
+            const TCCLKS_MCK_8: u32 = 1;
+            const WAVSEL_UP_RC: u32 = 2 << 13;
+            const WAVE: u32 = 1 << 15;
+            let steppers = StepGenerator::new((), &SEGMENTS);
+            cortex_m::interrupt::free(|cs| STEPPERS.borrow(cs).replace(Some((steppers, 0))));
+            write(PMC_PCER0, 1 << ID_TC0);
+            write(TC_CMR, TCCLKS_MCK_8 | WAVSEL_UP_RC | WAVE);
+            write(TC_IER, TC_CPCS);
+            write(NVIC_ISER0, 1 << ID_TC0);
+        }
+
+        let cp = cortex_m::Peripherals::take().unwrap_or_else(|| unreachable!());
+        super::start_systick(cp.SYST, MCK);
+
+        Self {
+            serial: Some((RX_BUFFER.stream(), TX_BUFFER.sink(start_transmission))),
+            steppers: Some(SEGMENTS.sink(start_stepping)),
+        }
+    }
+}
+
+impl Platform for DuetWifi {
+    type SerialIn = RxStream;
+
+    fn name(&self) -> &'static str {
+        "duet-wifi"
+    }
+    fn sysclk(&self) -> u32 {
+        MCK
+    }
+    fn serial(&mut self) -> (Self::SerialIn, TxSink) {
+        self.serial.take().unwrap_or_else(|| unreachable!())
+    }
+    fn rx_stats(&self) -> Stats {
+        RX_BUFFER.stats()
+    }
+    fn steppers(&mut self) -> SegmentSink {
+        self.steppers.take().unwrap_or_else(|| unreachable!())
+    }
+}
@@ -0,0 +1,131 @@
+//! Board standing in for a real one in the host tests.
+//!
+//! The G-code comes from a script and the responses are collected on the thread running the
+//! firmware. Segments are executed as soon as they are queued, ignoring their timing, by a step
+//! generator driving [`RecordingPins`]: the endstops and the probe are simulated like on the
+//! `platform-sim` board, without making the tests wait for the moves.
+//!
+//! Time is virtual: whenever the firmware is idle, the mock skips to the deadline of the next
+//! timer, so that the scripts may dwell without making the tests wait.
+
+use std::cell::RefCell;
+use std::vec;
+
+use futures::stream;
+
+use super::sim::RecordingPins;
+use super::Platform;
+use crate::ring_buffer::Stats;
+use crate::serial::{self, TxBuffer, TxSink};
+use crate::state::Workspace;
+use crate::stepper::{SegmentQueue, SegmentSink, StepGenerator};
+
+thread_local! {
+    /// Where the mock running on this thread sends its responses, and what it sent so far.
+    static OUTPUT: RefCell<Option<(&'static TxBuffer, Vec<u8>)>> = const { RefCell::new(None) };
+    /// Step generator of the mock running on this thread.
+    static GENERATOR: RefCell<Option<StepGenerator<RecordingPins>>> = const { RefCell::new(None) };
+}
+
+/// Stands for the uart's transmitter.
+struct Recorder<'a>(&'a mut Vec<u8>);
+
+impl embedded_hal::serial::Write<u8> for Recorder<'_> {
+    type Error = ();
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        self.0.push(byte);
+        Ok(())
+    }
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+fn start_transmission() {
+    OUTPUT.with(|output| {
+        if let Some((buffer, bytes)) = output.borrow_mut().as_mut() {
+            serial::transmit(&mut Recorder(bytes), buffer);
+        }
+    });
+}
+
+fn start_stepping() {
+    GENERATOR.with(|generator| {
+        if let Some(generator) = generator.borrow_mut().as_mut() {
+            while generator.tick().is_some() {}
+        }
+    });
+}
+
+pub(crate) struct Mock {
+    script: Option<Vec<u8>>,
+    steppers: Option<SegmentSink>,
+}
+
+impl Mock {
+    /// A board receiving `script`, whose tool starts at `tool`, in mm, above the bed whose height
+    /// at X=0, Y=0 and slopes along X and Y are `bed`.
+    ///
+    /// There must be only one mock per thread at a time.
+    pub fn new(script: &str, tool: [f32; 3], bed: [f32; 3]) -> Self {
+        // Each mock has its own queues, the tests run concurrently.
+        let segments: &'static SegmentQueue = Box::leak(Box::new(SegmentQueue::new()));
+        let pins = RecordingPins::new(None, tool, bed);
+        GENERATOR.with(|generator| {
+            generator.replace(Some(StepGenerator::new(pins, segments)));
+        });
+        Self {
+            script: Some(script.as_bytes().to_vec()),
+            steppers: Some(segments.sink(start_stepping)),
+        }
+    }
+
+    /// The responses sent so far.
+    pub fn output(&self) -> String {
+        OUTPUT.with(|output| match &*output.borrow() {
+            Some((_, bytes)) => String::from_utf8_lossy(bytes).into_owned(),
+            None => String::new(),
+        })
+    }
+
+    /// Position of the tool, in mm.
+    pub fn tool(&self) -> Workspace<f32> {
+        GENERATOR.with(|generator| {
+            let mut generator = generator.borrow_mut();
+            let generator = generator.as_mut().unwrap_or_else(|| unreachable!());
+            generator
+                .pins_mut()
+                .tool()
+                .unwrap_or_else(|| unreachable!())
+        })
+    }
+}
+
+impl Platform for Mock {
+    type SerialIn = stream::Iter<vec::IntoIter<Result<u8, serial::Error>>>;
+
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+    fn sysclk(&self) -> u32 {
+        1_000_000_000
+    }
+    fn serial(&mut self) -> (Self::SerialIn, TxSink) {
+        let script = self.script.take().unwrap_or_else(|| unreachable!());
+        let bytes: Vec<_> = script.into_iter().map(Ok).collect();
+        let buffer: &'static TxBuffer = Box::leak(Box::new(TxBuffer::new()));
+        OUTPUT.with(|output| output.replace(Some((buffer, Vec::new()))));
+        (stream::iter(bytes), buffer.sink(start_transmission))
+    }
+    fn rx_stats(&self) -> Stats {
+        // The script is streamed as it is parsed, nothing is ever buffered.
+        Stats::default()
+    }
+    fn steppers(&mut self) -> SegmentSink {
+        self.steppers.take().unwrap_or_else(|| unreachable!())
+    }
+    fn idle(&self) {
+        crate::time::skip_to_next_deadline();
+    }
+}
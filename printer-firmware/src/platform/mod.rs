@@ -1,6 +1,142 @@
-//! Platform should provide members for :
-//! - rx : serial interface source of gcode
-//! - tx : serial interface for debug messages
+//! Board support.
+//!
+//! Each board implements [`Platform`] and exactly one of them is exported as `Board` depending on
+//! the `platform-*` feature selected at build time.
+//! The host tests run the firmware on a mock board instead, see `mock.rs`.
+//!
+//! Boards are also responsible for driving the time base by calling [`crate::time::tick`] at
+//! [`crate::time::TICK_HZ`]. Cortex-M boards only need to hand their SysTick over to
+//! [`start_systick`].
+//!
+//! The serial link is interrupt driven: the board's uart interrupt handler feeds a static
+//! [`crate::serial::RxBuffer`] (see [`crate::serial::receive`]) and drains a static
+//! [`crate::serial::TxBuffer`] (see [`crate::serial::transmit`]).
+//!
+//! Step pulses are generated by a [`crate::stepper::StepGenerator`] run from a timer interrupt
+//! programmed with the delays it returns.
+
+/// Defines the stepping of a STM32 board whose drivers, endstops and probe are wired as on a CNC
+/// shield v3 plugged in its Arduino headers, from the types of its pins: the `Pins` struct, the
+/// `STEPPERS` generator run by the TIM2 interrupt and the `start_stepping` function to hand to
+/// the segment sink.
+///
+/// The board must have its HAL's `interrupt` attribute and `TIM2` in scope, and set TIM2 up to
+/// count at [`crate::stepper::STEP_TIMER_HZ`] and interrupt at the end of each period.
+#[cfg(any(feature = "platform-nucleo-f401re", feature = "platform-disco-l475"))]
+macro_rules! shield_stepping {
+    (
+        x_step: $x_step:ty,
+        y_step: $y_step:ty,
+        z_step: $z_step:ty,
+        e_step: $e_step:ty,
+        x_dir: $x_dir:ty,
+        y_dir: $y_dir:ty,
+        z_dir: $z_dir:ty,
+        e_dir: $e_dir:ty,
+        enable: $enable:ty,
+        x_endstop: $x_endstop:ty,
+        y_endstop: $y_endstop:ty,
+        z_endstop: $z_endstop:ty,
+        probe: $probe:ty $(,)?
+    ) => {
+        static STEPPERS: cortex_m::interrupt::Mutex<
+            core::cell::RefCell<Option<$crate::stepper::StepGenerator<Pins>>>,
+        > = cortex_m::interrupt::Mutex::new(core::cell::RefCell::new(None));
+
+        struct Pins {
+            x_step: $x_step,
+            y_step: $y_step,
+            z_step: $z_step,
+            e_step: $e_step,
+            x_dir: $x_dir,
+            y_dir: $y_dir,
+            z_dir: $z_dir,
+            e_dir: $e_dir,
+            /// Active low, shared by all the drivers.
+            enable: $enable,
+            x_endstop: $x_endstop,
+            y_endstop: $y_endstop,
+            z_endstop: $z_endstop,
+            probe: $probe,
+        }
+
+        impl $crate::stepper::StepperPins for Pins {
+            fn set_directions(&mut self, forward: [bool; $crate::config::AXIS_COUNT]) {
+                use $crate::stepper::set_pin;
+                set_pin(&mut self.x_dir, forward[0]);
+                set_pin(&mut self.y_dir, forward[1]);
+                set_pin(&mut self.z_dir, forward[2]);
+                set_pin(&mut self.e_dir, forward[3]);
+            }
+            fn set_steps(&mut self, step: [bool; $crate::config::AXIS_COUNT]) {
+                use $crate::stepper::set_pin;
+                if step[0] {
+                    set_pin(&mut self.x_step, true);
+                }
+                if step[1] {
+                    set_pin(&mut self.y_step, true);
+                }
+                if step[2] {
+                    set_pin(&mut self.z_step, true);
+                }
+                if step[3] {
+                    set_pin(&mut self.e_step, true);
+                }
+            }
+            fn clear_steps(&mut self) {
+                use $crate::stepper::set_pin;
+                set_pin(&mut self.x_step, false);
+                set_pin(&mut self.y_step, false);
+                set_pin(&mut self.z_step, false);
+                set_pin(&mut self.e_step, false);
+            }
+            fn set_enabled(&mut self, enabled: bool) {
+                $crate::stepper::set_pin(&mut self.enable, !enabled);
+            }
+            fn endstop(&mut self, axis: usize) -> bool {
+                use $crate::stepper::read_pin;
+                match axis {
+                    0 => read_pin(&self.x_endstop),
+                    1 => read_pin(&self.y_endstop),
+                    _ => read_pin(&self.z_endstop),
+                }
+            }
+            fn probe(&mut self) -> bool {
+                $crate::stepper::read_pin(&self.probe)
+            }
+        }
+
+        fn start_stepping() {
+            cortex_m::interrupt::free(|_| {
+                // SAFETY: TIM2 is otherwise only used by its interrupt handler, which is masked.
+                let tim = unsafe { &*TIM2::ptr() };
+                if tim.cr1.read().cen().bit_is_clear() {
+                    tim.arr
+                        .write(|w| unsafe { w.bits($crate::stepper::MIN_STEP_INTERVAL - 1) });
+                    tim.cnt.write(|w| unsafe { w.bits(0) });
+                    tim.cr1.modify(|_, w| w.cen().set_bit());
+                }
+            });
+        }
+
+        #[interrupt]
+        fn TIM2() {
+            cortex_m::interrupt::free(|cs| {
+                // SAFETY: TIM2 is only used by this handler and `start_stepping` while it is
+                // masked.
+                let tim = unsafe { &*TIM2::ptr() };
+                tim.sr.modify(|_, w| w.uif().clear_bit());
+                if let Some(steppers) = STEPPERS.borrow(cs).borrow_mut().as_mut() {
+                    match steppers.tick() {
+                        // Without preload, the new period applies to the one that just started.
+                        Some(interval) => tim.arr.write(|w| unsafe { w.bits(interval - 1) }),
+                        None => tim.cr1.modify(|_, w| w.cen().clear_bit()),
+                    }
+                }
+            });
+        }
+    };
+}
 
 #[cfg(feature = "platform-nucleo-f401re")]
 mod nucleo_f401re;
@@ -8,8 +144,73 @@ mod nucleo_f401re;
 #[cfg(feature = "platform-disco-l475")]
 mod disco_l475;
 
+#[cfg(feature = "platform-duet-wifi")]
+mod duet_wifi;
+
+#[cfg(feature = "platform-sim")]
+mod sim;
+
+#[cfg(all(test, feature = "platform-sim"))]
+pub(crate) mod mock;
+
 #[cfg(feature = "platform-nucleo-f401re")]
-pub(crate) use nucleo_f401re::Platform;
+pub(crate) use nucleo_f401re::NucleoF401re as Board;
 
 #[cfg(feature = "platform-disco-l475")]
-pub(crate) use disco_l475::Platform;
+pub(crate) use disco_l475::DiscoL475 as Board;
+
+#[cfg(feature = "platform-duet-wifi")]
+pub(crate) use duet_wifi::DuetWifi as Board;
+
+#[cfg(feature = "platform-sim")]
+pub(crate) use sim::Sim as Board;
+
+/// What the firmware core expects from a board.
+///
+/// Peripherals that end up owned by a task (eg. the serial link) are handed over by value and can
+/// only be taken once.
+pub(crate) trait Platform {
+    /// Serial interface the G-code is received from.
+    type SerialIn: futures::Stream<Item = Result<u8, crate::serial::Error>> + Unpin;
+
+    /// Board name as reported to the host.
+    fn name(&self) -> &'static str;
+    /// Frequency of the core clock in Hz.
+    fn sysclk(&self) -> u32;
+    /// Hands over the serial link, the sink is where responses and debug messages are sent to.
+    ///
+    /// Panics if called more than once.
+    fn serial(&mut self) -> (Self::SerialIn, crate::serial::TxSink);
+    /// Usage statistics of the buffer the serial input waits in for the parser.
+    fn rx_stats(&self) -> crate::ring_buffer::Stats;
+    /// Hands over the step generator's queue.
+    ///
+    /// Panics if called more than once.
+    fn steppers(&mut self) -> crate::stepper::SegmentSink;
+    /// Called whenever no task is ready to run, before the core is parked.
+    fn idle(&self) {}
+    /// Stops the firmware once all its tasks completed.
+    ///
+    /// This only happens on the host, once the input is closed.
+    fn shutdown(&mut self) -> ! {
+        unreachable!()
+    }
+}
+
+/// Configures the SysTick to raise an exception at `time::TICK_HZ`.
+#[cfg(not(feature = "platform-sim"))]
+fn start_systick(mut syst: cortex_m::peripheral::SYST, sysclk: u32) {
+    use cortex_m::peripheral::syst::SystClkSource;
+
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(sysclk / crate::time::TICK_HZ - 1);
+    syst.clear_current();
+    syst.enable_counter();
+    syst.enable_interrupt();
+}
+
+#[cfg(not(feature = "platform-sim"))]
+#[cortex_m_rt::exception]
+fn SysTick() {
+    crate::time::tick();
+}
@@ -1,19 +1,90 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
 use stm32f4xx_hal::{
+    gpio::{
+        gpioa::{PA10, PA5, PA6, PA7, PA8, PA9},
+        gpiob::{PB10, PB3, PB4, PB5, PB6},
+        gpioc::{PC0, PC7},
+        Input, Output, PullUp, PushPull,
+    },
     prelude::*,
-    serial::{self, Rx, Serial, Tx},
-    stm32::{Peripherals, USART2},
+    rcc::Clocks,
+    serial::{self, Event, Rx, Serial, Tx},
+    stm32::{interrupt, Interrupt, Peripherals, RCC, TIM2, USART2},
 };
 
-pub(crate) struct Platform {
-    pub sout: Tx<USART2>,
-    pub sin: Rx<USART2>,
-    pub name: &'static str,
+use super::Platform;
+use crate::ring_buffer::Stats;
+use crate::serial::{RxBuffer, RxStream, TxBuffer, TxSink};
+use crate::stepper::{SegmentQueue, SegmentSink, StepGenerator, StepperPins, STEP_TIMER_HZ};
+
+static SERIAL: Mutex<RefCell<Option<(Rx<USART2>, Tx<USART2>)>>> = Mutex::new(RefCell::new(None));
+static RX_BUFFER: RxBuffer = RxBuffer::new();
+static TX_BUFFER: TxBuffer = TxBuffer::new();
+
+static SEGMENTS: SegmentQueue = SegmentQueue::new();
+
+// Wired as on a CNC shield v3 plugged in the Arduino headers, the extruder being on the A slot
+// (D12/D13).
+shield_stepping! {
+    x_step: PA10<Output<PushPull>>, // D2
+    y_step: PB3<Output<PushPull>>,  // D3
+    z_step: PB5<Output<PushPull>>,  // D4
+    e_step: PA6<Output<PushPull>>,  // D12
+    x_dir: PB4<Output<PushPull>>,   // D5
+    y_dir: PB10<Output<PushPull>>,  // D6
+    z_dir: PA8<Output<PushPull>>,   // D7
+    e_dir: PA5<Output<PushPull>>,   // D13
+    enable: PA9<Output<PushPull>>,  // D8
+    x_endstop: PC7<Input<PullUp>>,  // D9
+    y_endstop: PB6<Input<PullUp>>,  // D10
+    z_endstop: PA7<Input<PullUp>>,  // D11
+    probe: PC0<Input<PullUp>>,      // A5
+}
+
+impl From<serial::Error> for crate::serial::Error {
+    fn from(e: serial::Error) -> Self {
+        match e {
+            serial::Error::Overrun => Self::Overrun,
+            serial::Error::Framing => Self::Framing,
+            serial::Error::Parity => Self::Parity,
+            _ => Self::Noise,
+        }
+    }
+}
+
+fn start_transmission() {
+    // SAFETY: CR1 is otherwise only modified by the interrupt handler.
+    cortex_m::interrupt::free(|_| unsafe {
+        (*USART2::ptr()).cr1.modify(|_, w| w.txeie().set_bit())
+    });
+}
+
+#[interrupt]
+fn USART2() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some((rx, tx)) = SERIAL.borrow(cs).borrow_mut().as_mut() {
+            crate::serial::receive(rx, &RX_BUFFER);
+            if crate::serial::transmit(tx, &TX_BUFFER) {
+                // SAFETY: interrupts are masked, nothing else can be modifying CR1.
+                unsafe { (*USART2::ptr()).cr1.modify(|_, w| w.txeie().clear_bit()) };
+            }
+        }
+    });
+}
+
+pub(crate) struct NucleoF401re {
+    serial: Option<(RxStream, TxSink)>,
+    steppers: Option<SegmentSink>,
+    clocks: Clocks,
 }
 
-impl Platform {
+impl NucleoF401re {
     pub fn take() -> Self {
         // Get access to the device specific peripherals from the peripheral access crate
         let p = Peripherals::take().unwrap_or_else(|| unreachable!());
+        let cp = cortex_m::Peripherals::take().unwrap_or_else(|| unreachable!());
 
         // Take ownership over the raw flash and rcc devices and convert them
         // into the corresponding HAL structs
@@ -25,6 +96,8 @@ impl Platform {
 
         // Acquire the GPIOC peripheral
         let gpioa = p.GPIOA.split();
+        let gpiob = p.GPIOB.split();
+        let gpioc = p.GPIOC.split();
 
         let tx = gpioa.pa2.into_alternate_af7();
         let rx = gpioa.pa3.into_alternate_af7();
@@ -35,13 +108,76 @@ impl Platform {
             serial::config::Config::default().baudrate(115_200.bps()),
             clocks,
         )
-        .map(|serial| serial.split())
+        .map(|mut serial| {
+            serial.listen(Event::Rxne);
+            serial.split()
+        })
         .unwrap_or_else(|_| unreachable!());
 
+        cortex_m::interrupt::free(|cs| SERIAL.borrow(cs).replace(Some((rx, tx))));
+        // SAFETY: the handler only touches SERIAL and the buffers which are ready to be used.
+        unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::USART2) };
+
+        let mut pins = Pins {
+            x_step: gpioa.pa10.into_push_pull_output(),
+            y_step: gpiob.pb3.into_push_pull_output(),
+            z_step: gpiob.pb5.into_push_pull_output(),
+            e_step: gpioa.pa6.into_push_pull_output(),
+            x_dir: gpiob.pb4.into_push_pull_output(),
+            y_dir: gpiob.pb10.into_push_pull_output(),
+            z_dir: gpioa.pa8.into_push_pull_output(),
+            e_dir: gpioa.pa5.into_push_pull_output(),
+            enable: gpioa.pa9.into_push_pull_output(),
+            x_endstop: gpioc.pc7.into_pull_up_input(),
+            y_endstop: gpiob.pb6.into_pull_up_input(),
+            z_endstop: gpioa.pa7.into_pull_up_input(),
+            probe: gpioc.pc0.into_pull_up_input(),
+        };
+        pins.set_enabled(false);
+        let steppers = StepGenerator::new(pins, &SEGMENTS);
+        cortex_m::interrupt::free(|cs| STEPPERS.borrow(cs).replace(Some(steppers)));
+
+        // TIM2 counts at STEP_TIMER_HZ and interrupts at the end of each step interval. Its clock
+        // runs twice as fast as APB1 when the latter is divided.
+        let timclk = clocks.pclk1().0 * if clocks.ppre1() == 1 { 1 } else { 2 };
+        // SAFETY: RCC was consumed by the HAL which leaves APB1ENR's TIM2EN alone.
+        unsafe { (*RCC::ptr()).apb1enr.modify(|_, w| w.tim2en().set_bit()) };
+        let tim = p.TIM2;
+        tim.psc
+            .write(|w| unsafe { w.bits(timclk / STEP_TIMER_HZ - 1) });
+        // Load the prescaler right away.
+        tim.egr.write(|w| w.ug().set_bit());
+        tim.sr.modify(|_, w| w.uif().clear_bit());
+        tim.dier.write(|w| w.uie().set_bit());
+        // SAFETY: the handler only touches TIM2 and STEPPERS which are ready to be used.
+        unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::TIM2) };
+
+        super::start_systick(cp.SYST, clocks.sysclk().0);
+
         Self {
-            sin: rx,
-            sout: tx,
-            name: "nucleo_f401re",
+            serial: Some((RX_BUFFER.stream(), TX_BUFFER.sink(start_transmission))),
+            steppers: Some(SEGMENTS.sink(start_stepping)),
+            clocks,
         }
     }
 }
+
+impl Platform for NucleoF401re {
+    type SerialIn = RxStream;
+
+    fn name(&self) -> &'static str {
+        "nucleo_f401re"
+    }
+    fn sysclk(&self) -> u32 {
+        self.clocks.sysclk().0
+    }
+    fn serial(&mut self) -> (Self::SerialIn, TxSink) {
+        self.serial.take().unwrap_or_else(|| unreachable!())
+    }
+    fn rx_stats(&self) -> Stats {
+        RX_BUFFER.stats()
+    }
+    fn steppers(&mut self) -> SegmentSink {
+        self.steppers.take().unwrap_or_else(|| unreachable!())
+    }
+}
@@ -0,0 +1,318 @@
+//! Host simulation platform.
+//!
+//! Runs the firmware as a regular Linux process. G-code is read from stdin and responses are
+//! written to stdout, unless a path (eg. one end of a `socat` pseudo-terminal pair) is given as
+//! the first command line argument, in which case that file is used for both directions.
+//!
+//! The time base is driven by a thread following the host's clock.
+//!
+//! A reader thread stands for the uart's receive interrupt and feeds the received bytes to an
+//! [`RxBuffer`]. Unlike a real host it never overruns it: it waits for room instead. Likewise a
+//! writer thread drains the [`TxBuffer`].
+//!
+//! The step generator is run by a thread following the host's clock too. If the `SIM_STEP_LOG`
+//! environment variable is set, the generated pulse train is recorded to the file it names, one
+//! line per event: the time in µs followed by either the axis and direction of a step (eg. `X+`)
+//! or `enable`/`disable`.
+//!
+//! The endstops are simulated from the steps generated, the tool starting at the position the
+//! `SIM_POSITION` environment variable gives as `X,Y,Z` in mm, or at 10mm from each of them. They
+//! trigger once the tool reaches 0 on their axis. The probe triggers once the tool reaches the bed,
+//! a plane whose height is given by the `SIM_BED` environment variable as `Z,SLOPE_X,SLOPE_Y`:
+//! its height at X=0, Y=0, in mm, and how much it rises per mm along X and Y. It is flat at 0
+//! otherwise.
+//!
+//! Once the input is closed, the process exits when the firmware has executed everything it
+//! received. This lets the simulation be driven from a script:
+//! `cargo run --no-default-features --features platform-sim < print.gcode`.
+
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read as _};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::{env, process, thread};
+
+use super::Platform;
+use crate::config::{
+    AXIS_COUNT, AXIS_NAMES, ENDSTOP_TRIGGERED_HIGH, KINEMATICS, PROBE_TRIGGERED_HIGH, STEPS_PER_MM,
+};
+use crate::ring_buffer::Stats;
+use crate::serial::{self, RxBuffer, RxStream, TxBuffer, TxSink};
+use crate::state::Workspace;
+use crate::stepper::{SegmentQueue, SegmentSink, StepGenerator, StepperPins, STEP_TIMER_HZ};
+
+type Output = Arc<Mutex<Box<dyn io::Write + Send>>>;
+type Steppers = Arc<Mutex<StepGenerator<RecordingPins>>>;
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static RX_BUFFER: RxBuffer = RxBuffer::new();
+static TX_BUFFER: TxBuffer = TxBuffer::new();
+static SEGMENTS: SegmentQueue = SegmentQueue::new();
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Stands for the uart's transmitter.
+struct HostTx<'a>(&'a mut (dyn io::Write + Send));
+
+impl embedded_hal::serial::Write<u8> for HostTx<'_> {
+    type Error = io::Error;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        self.0.write_all(&[byte]).map_err(nb::Error::Other)
+    }
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.0.flush().map_err(nb::Error::Other)
+    }
+}
+
+/// Sends everything that was queued so far.
+fn drain(output: &Output) {
+    use embedded_hal::serial::Write as _;
+
+    let mut output = lock(output);
+    let mut tx = HostTx(&mut **output);
+    serial::transmit(&mut tx, &TX_BUFFER);
+    // Output errors are ignored, just like a uart with nothing connected.
+    let _ = tx.flush();
+}
+
+/// Records the pulse train instead of driving pins, and simulates the endstops and the probe.
+pub(super) struct RecordingPins {
+    log: Option<Box<dyn io::Write + Send>>,
+    /// Time of the current step event, in timer ticks.
+    now: u64,
+    forward: [bool; AXIS_COUNT],
+    /// Position of each motor, in steps.
+    motors: [i64; 3],
+    /// Height of the bed at X=0, Y=0 and its slopes along X and Y.
+    bed: [f32; 3],
+}
+
+impl RecordingPins {
+    /// Pins of a machine whose tool starts at `tool`, in mm, above the bed whose height at X=0,
+    /// Y=0 and slopes along X and Y are `bed`.
+    pub(super) fn new(
+        log: Option<Box<dyn io::Write + Send>>,
+        tool: [f32; 3],
+        bed: [f32; 3],
+    ) -> Self {
+        let [x, y, z] = tool;
+        let motors = KINEMATICS
+            .inverse(Workspace { x, y, z })
+            .unwrap_or_else(|| panic!("position {:?} is out of reach", tool));
+        let mut steps = [0; 3];
+        for (axis, (s, v)) in steps.iter_mut().zip(motors.iter()).enumerate() {
+            *s = (*v * STEPS_PER_MM[axis]).round() as i64;
+        }
+        Self {
+            log,
+            now: 0,
+            forward: [true; AXIS_COUNT],
+            motors: steps,
+            bed,
+        }
+    }
+
+    fn record(&mut self, event: impl Display) {
+        if let Some(log) = &mut self.log {
+            let us = self.now * 1_000_000 / u64::from(STEP_TIMER_HZ);
+            let _ = writeln!(log, "{} {}", us, event);
+        }
+    }
+
+    /// Position of the tool, in mm.
+    pub(super) fn tool(&self) -> Option<Workspace<f32>> {
+        let mut motors = [0.; 3];
+        for (axis, (mm, steps)) in motors.iter_mut().zip(self.motors.iter()).enumerate() {
+            *mm = *steps as f32 / STEPS_PER_MM[axis];
+        }
+        KINEMATICS.forward(motors)
+    }
+}
+
+impl StepperPins for RecordingPins {
+    fn set_directions(&mut self, forward: [bool; AXIS_COUNT]) {
+        self.forward = forward;
+    }
+    fn set_steps(&mut self, step: [bool; AXIS_COUNT]) {
+        for axis in (0..AXIS_COUNT).filter(|axis| step[*axis]) {
+            if let Some(motor) = self.motors.get_mut(axis) {
+                *motor += if self.forward[axis] { 1 } else { -1 };
+            }
+            let direction = if self.forward[axis] { '+' } else { '-' };
+            self.record(format_args!("{}{}", AXIS_NAMES[axis], direction));
+        }
+    }
+    fn clear_steps(&mut self) {}
+    fn set_enabled(&mut self, enabled: bool) {
+        self.record(if enabled { "enable" } else { "disable" });
+    }
+    fn endstop(&mut self, axis: usize) -> bool {
+        let triggered = match self.tool() {
+            Some(tool) => [tool.x, tool.y, tool.z][axis] <= 0.,
+            None => false,
+        };
+        triggered == ENDSTOP_TRIGGERED_HIGH[axis]
+    }
+    fn probe(&mut self) -> bool {
+        let [z, slope_x, slope_y] = self.bed;
+        let triggered = match self.tool() {
+            Some(tool) => tool.z <= z + slope_x * tool.x + slope_y * tool.y,
+            None => false,
+        };
+        triggered == PROBE_TRIGGERED_HIGH
+    }
+}
+
+/// Parses the comma separated values of the `name` environment variable into `values`, leaving
+/// them untouched if it is not set.
+fn parse_env(name: &str, values: &mut [f32]) {
+    if let Ok(list) = env::var(name) {
+        for (v, s) in values.iter_mut().zip(list.split(',')) {
+            *v = s
+                .trim()
+                .parse()
+                .unwrap_or_else(|e| panic!("invalid {} {:?}: {}", name, list, e));
+        }
+    }
+}
+
+pub(crate) struct Sim {
+    serial: Option<(RxStream, TxSink)>,
+    steppers: Option<SegmentSink>,
+    output: Output,
+    generator: Steppers,
+}
+
+impl Sim {
+    pub fn take() -> Self {
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            unreachable!()
+        }
+
+        let (input, output): (Box<dyn io::Read + Send>, Box<dyn io::Write + Send>) =
+            match env::args().nth(1) {
+                Some(path) => {
+                    let port = OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(&path)
+                        .unwrap_or_else(|e| panic!("failed to open {}: {}", path, e));
+                    let reader = port
+                        .try_clone()
+                        .unwrap_or_else(|e| panic!("failed to clone {}: {}", path, e));
+                    (Box::new(reader), Box::new(io::BufWriter::new(port)))
+                }
+                None => (Box::new(io::stdin()), Box::new(io::stdout())),
+            };
+        let output = Arc::new(Mutex::new(output));
+
+        let log = env::var_os("SIM_STEP_LOG").map(|path| {
+            let file = File::create(&path)
+                .unwrap_or_else(|e| panic!("failed to create {:?}: {}", path, e));
+            Box::new(io::BufWriter::new(file)) as Box<dyn io::Write + Send>
+        });
+        let mut tool = [10.; 3];
+        parse_env("SIM_POSITION", &mut tool);
+        let mut bed = [0.; 3];
+        parse_env("SIM_BED", &mut bed);
+        let pins = RecordingPins::new(log, tool, bed);
+        let generator = Arc::new(Mutex::new(StepGenerator::new(pins, &SEGMENTS)));
+
+        thread::spawn(move || {
+            // A read error is handled like the end of the input.
+            for byte in io::BufReader::new(input).bytes().map_while(Result::ok) {
+                while RX_BUFFER.is_full() {
+                    thread::sleep(Duration::from_micros(100));
+                }
+                RX_BUFFER.push(byte);
+            }
+            RX_BUFFER.close();
+        });
+
+        let writer_output = Arc::clone(&output);
+        thread::spawn(move || loop {
+            drain(&writer_output);
+            thread::sleep(Duration::from_micros(100));
+        });
+
+        thread::spawn(|| {
+            let period = Duration::from_secs(1) / crate::time::TICK_HZ;
+            let mut next = Instant::now() + period;
+            loop {
+                thread::sleep(next.saturating_duration_since(Instant::now()));
+                crate::time::tick();
+                next += period;
+            }
+        });
+
+        let timer_generator = Arc::clone(&generator);
+        thread::spawn(move || {
+            let start = Instant::now();
+            let ticks = |d: Duration| d.as_micros() as u64 * u64::from(STEP_TIMER_HZ) / 1_000_000;
+            // Time of the next step event, in timer ticks since `start`.
+            let mut now = 0;
+            loop {
+                let next = {
+                    let mut generator = lock(&timer_generator);
+                    generator.pins_mut().now = now;
+                    generator.tick()
+                };
+                match next {
+                    Some(interval) => now += u64::from(interval),
+                    None => {
+                        // Idle, resume from the current time once there is a segment to run.
+                        thread::sleep(Duration::from_micros(100));
+                        now = now.max(ticks(start.elapsed()));
+                    }
+                }
+                // Sleeping before every step event would be too coarse, catch up by the ms.
+                let due = Duration::from_micros(now * 1_000_000 / u64::from(STEP_TIMER_HZ));
+                let elapsed = start.elapsed();
+                if due > elapsed + Duration::from_millis(1) {
+                    thread::sleep(due - elapsed);
+                }
+            }
+        });
+
+        // The writer and step generator threads poll their queues, there is nothing to start.
+        Self {
+            serial: Some((RX_BUFFER.stream(), TX_BUFFER.sink(|| {}))),
+            steppers: Some(SEGMENTS.sink(|| {})),
+            output,
+            generator,
+        }
+    }
+}
+
+impl Platform for Sim {
+    type SerialIn = RxStream;
+
+    fn name(&self) -> &'static str {
+        "sim"
+    }
+    fn sysclk(&self) -> u32 {
+        // nominal value, the host's clock has no meaning for the firmware.
+        1_000_000_000
+    }
+    fn serial(&mut self) -> (Self::SerialIn, TxSink) {
+        self.serial.take().unwrap_or_else(|| unreachable!())
+    }
+    fn rx_stats(&self) -> Stats {
+        RX_BUFFER.stats()
+    }
+    fn steppers(&mut self) -> SegmentSink {
+        self.steppers.take().unwrap_or_else(|| unreachable!())
+    }
+    fn shutdown(&mut self) -> ! {
+        drain(&self.output);
+        if let Some(log) = &mut lock(&self.generator).pins_mut().log {
+            let _ = log.flush();
+        }
+        process::exit(0)
+    }
+}
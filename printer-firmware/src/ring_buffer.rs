@@ -0,0 +1,268 @@
+//! Lock-free single producer single consumer ring buffer.
+//!
+//! One end typically is an interrupt handler and the other one a task (eg. bytes received by a
+//! uart, step segments fed to the step generator). Both ends only ever update their own counter so
+//! no critical section is needed.
+//!
+//! # Overflow policy
+//!
+//! When the buffer is full, incoming elements are dropped and the buffer content is left
+//! untouched: for a serial link, the oldest bytes may already belong to a line being processed
+//! while the newest ones will have to be sent again anyway. Dropped elements are counted in
+//! [`Stats::dropped`].
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Usage statistics, accumulated since the buffer was created.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Highest number of elements ever held at once.
+    pub high_water_mark: usize,
+    /// Number of elements dropped because the buffer was full.
+    pub dropped: usize,
+}
+
+pub struct RingBuffer<T, const N: usize> {
+    buffer: UnsafeCell<MaybeUninit<[T; N]>>,
+    // Counters run modulo 2N so that a full buffer can be told apart from an empty one without
+    // sacrificing a slot.
+    wr: AtomicUsize, // write counter, only updated by the producer
+    rd: AtomicUsize, // read counter, only updated by the consumer
+    high_water_mark: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+// SAFETY: a slot of `buffer` is only written by the producer while it is not visible to the
+// consumer and only read by the consumer while the producer cannot write it.
+unsafe impl<T: Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new(MaybeUninit::uninit()),
+            wr: AtomicUsize::new(0),
+            rd: AtomicUsize::new(0),
+            high_water_mark: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    fn distance(wr: usize, rd: usize) -> usize {
+        (wr + 2 * N - rd) % (2 * N)
+    }
+    fn advance(counter: usize) -> usize {
+        (counter + 1) % (2 * N)
+    }
+
+    pub fn len(&self) -> usize {
+        Self::distance(
+            self.wr.load(Ordering::Acquire),
+            self.rd.load(Ordering::Acquire),
+        )
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    fn slot(&self, counter: usize) -> *mut T {
+        // SAFETY: `counter % N` is within the array.
+        unsafe {
+            (*self.buffer.get())
+                .as_mut_ptr()
+                .cast::<T>()
+                .add(counter % N)
+        }
+    }
+
+    /// Appends an element, giving it back if the buffer is full. Must only be called by the
+    /// producer.
+    pub fn push(&self, item: T) -> Result<(), T> {
+        let wr = self.wr.load(Ordering::Relaxed);
+        let len = Self::distance(wr, self.rd.load(Ordering::Acquire));
+        if len == N {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(item);
+        }
+        // SAFETY: the slot is not readable by the consumer until `wr` is updated.
+        unsafe { self.slot(wr).write(item) };
+        self.wr.store(Self::advance(wr), Ordering::Release);
+
+        if len + 1 > self.high_water_mark.load(Ordering::Relaxed) {
+            self.high_water_mark.store(len + 1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Returns the oldest element, leaving it in the buffer. Must only be called by the consumer.
+    pub fn peek(&self) -> Option<T> {
+        let rd = self.rd.load(Ordering::Relaxed);
+        if self.wr.load(Ordering::Acquire) == rd {
+            return None;
+        }
+        // SAFETY: the slot was written by the producer and is not writable until `rd` is updated.
+        Some(unsafe { self.slot(rd).read() })
+    }
+
+    /// Takes the oldest element out of the buffer. Must only be called by the consumer.
+    pub fn pop(&self) -> Option<T> {
+        let item = self.peek()?;
+        let rd = self.rd.load(Ordering::Relaxed);
+        self.rd.store(Self::advance(rd), Ordering::Release);
+        Some(item)
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            high_water_mark: self.high_water_mark.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::VecDeque;
+
+    const N: usize = 4;
+
+    #[test]
+    fn empty() {
+        let buffer = RingBuffer::<u8, N>::new();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.peek(), None);
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.stats(), Stats::default());
+    }
+
+    #[test]
+    fn holds_n_elements() {
+        let buffer = RingBuffer::<u8, N>::new();
+        for i in 0..N as u8 {
+            assert_eq!(buffer.push(i), Ok(()));
+        }
+        assert!(buffer.is_full());
+        assert_eq!(buffer.len(), N);
+        for i in 0..N as u8 {
+            assert_eq!(buffer.pop(), Some(i));
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn drops_newest_when_full() {
+        let buffer = RingBuffer::<u8, N>::new();
+        for i in 0..N as u8 {
+            buffer.push(i).unwrap();
+        }
+        assert_eq!(buffer.push(10), Err(10));
+        assert_eq!(buffer.push(11), Err(11));
+        // The content is left untouched.
+        for i in 0..N as u8 {
+            assert_eq!(buffer.pop(), Some(i));
+        }
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.stats().dropped, 2);
+    }
+
+    #[test]
+    fn wraps_around_the_counters() {
+        // Go around the 2N counters several times at every fill level, full included.
+        for fill in 0..=N {
+            let buffer = RingBuffer::<usize, N>::new();
+            for i in 0..fill {
+                buffer.push(i).unwrap();
+            }
+            for i in fill..fill + 5 * N {
+                if fill == N {
+                    assert!(buffer.is_full());
+                    assert_eq!(buffer.push(usize::MAX), Err(usize::MAX));
+                    assert_eq!(buffer.pop(), Some(i - fill));
+                    buffer.push(i).unwrap();
+                } else {
+                    buffer.push(i).unwrap();
+                    assert_eq!(buffer.pop(), Some(i - fill));
+                }
+                assert_eq!(buffer.len(), fill);
+                assert_eq!(buffer.is_empty(), fill == 0);
+                assert_eq!(buffer.is_full(), fill == N);
+            }
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let buffer = RingBuffer::<u8, N>::new();
+        buffer.push(0).unwrap();
+        buffer.push(1).unwrap();
+        buffer.push(2).unwrap();
+        buffer.pop();
+        buffer.pop();
+        buffer.push(3).unwrap();
+        assert_eq!(
+            buffer.stats(),
+            Stats {
+                high_water_mark: 3,
+                dropped: 0
+            }
+        );
+        for i in 0..4 {
+            let _ = buffer.push(i);
+        }
+        assert_eq!(
+            buffer.stats(),
+            Stats {
+                high_water_mark: N,
+                dropped: 2
+            }
+        );
+        // Emptying the buffer doesn't reset them.
+        while buffer.pop().is_some() {}
+        assert_eq!(buffer.stats().high_water_mark, N);
+        assert_eq!(buffer.stats().dropped, 2);
+    }
+
+    proptest! {
+        /// Any sequence of pushes (`Some`) and pops (`None`) behaves like a bounded queue dropping
+        /// the elements pushed while full.
+        #[test]
+        fn behaves_like_a_bounded_queue(ops in prop::collection::vec(any::<Option<u8>>(), 0..200)) {
+            let buffer = RingBuffer::<u8, N>::new();
+            let mut model = VecDeque::new();
+            let mut stats = Stats::default();
+            for op in ops {
+                match op {
+                    Some(v) if model.len() == N => {
+                        prop_assert_eq!(buffer.push(v), Err(v));
+                        stats.dropped += 1;
+                    }
+                    Some(v) => {
+                        prop_assert_eq!(buffer.push(v), Ok(()));
+                        model.push_back(v);
+                        stats.high_water_mark = stats.high_water_mark.max(model.len());
+                    }
+                    None => {
+                        prop_assert_eq!(buffer.peek(), model.front().copied());
+                        prop_assert_eq!(buffer.pop(), model.pop_front());
+                    }
+                }
+                prop_assert_eq!(buffer.len(), model.len());
+                prop_assert_eq!(buffer.stats(), stats);
+            }
+        }
+    }
+}
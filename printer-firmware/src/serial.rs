@@ -0,0 +1,303 @@
+//! Interrupt driven serial link.
+//!
+//! The uart interrupt handler moves the received bytes to an [`RxBuffer`] which wakes the task
+//! reading them through an [`RxStream`]. Bytes received while the buffer is full are dropped (see
+//! [`crate::ring_buffer`]) and reported as an [`Error::Overrun`].
+//!
+//! The other way around, tasks queue their output in a [`TxBuffer`] through a [`TxSink`], only
+//! waiting if it is full, and the uart interrupt handler drains it.
+
+use core::fmt::Write as _;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::{Context, Poll};
+
+use arrayvec::ArrayString;
+use futures::future;
+use futures::stream::Stream;
+use futures::task::AtomicWaker;
+
+use crate::ring_buffer::{RingBuffer, Stats};
+
+/// Number of received bytes that can wait for the parser.
+pub const RX_BUFFER_SIZE: usize = 128;
+/// Number of bytes that can wait for the uart to send them.
+pub const TX_BUFFER_SIZE: usize = 256;
+/// Longest formatted message [`TxSink::write_fmt`] accepts.
+const TX_LINE_SIZE: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// Bytes were lost, either in the uart or because the buffer was full.
+    Overrun,
+    Framing,
+    Parity,
+    Noise,
+}
+
+impl Error {
+    fn to_u8(self) -> u8 {
+        match self {
+            Error::Overrun => 1,
+            Error::Framing => 2,
+            Error::Parity => 3,
+            Error::Noise => 4,
+        }
+    }
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Error::Overrun),
+            2 => Some(Error::Framing),
+            3 => Some(Error::Parity),
+            4 => Some(Error::Noise),
+            _ => None,
+        }
+    }
+}
+
+pub struct RxBuffer {
+    buffer: RingBuffer<u8, RX_BUFFER_SIZE>,
+    error: AtomicU8,
+    closed: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl RxBuffer {
+    pub const fn new() -> Self {
+        Self {
+            buffer: RingBuffer::new(),
+            error: AtomicU8::new(0),
+            closed: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Only the host's producer can wait for room, the uart's has to drop the bytes.
+    #[cfg(feature = "platform-sim")]
+    pub fn is_full(&self) -> bool {
+        self.buffer.is_full()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.buffer.stats()
+    }
+
+    /// Stores a received byte. Must only be called by the producer.
+    ///
+    /// If the buffer is full, the byte is dropped and an overrun is reported.
+    pub fn push(&self, byte: u8) {
+        match self.buffer.push(byte) {
+            Ok(()) => self.waker.wake(),
+            Err(_) => self.report(Error::Overrun),
+        }
+    }
+
+    /// Reports a reception error. Only the first one is kept until the consumer gets it.
+    pub fn report(&self, error: Error) {
+        let _ = self
+            .error
+            .compare_exchange(0, error.to_u8(), Ordering::AcqRel, Ordering::Acquire);
+        self.waker.wake();
+    }
+
+    /// Signals the end of the input, the stream ends once the buffer is drained.
+    ///
+    /// This only makes sense on the host where the input can be closed.
+    #[cfg(feature = "platform-sim")]
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    /// Polls for the next byte. Must only be called by the consumer.
+    fn poll_next(&self, cx: &mut Context<'_>) -> Poll<Option<Result<u8, Error>>> {
+        self.waker.register(cx.waker());
+        if let Some(error) = Error::from_u8(self.error.swap(0, Ordering::AcqRel)) {
+            return Poll::Ready(Some(Err(error)));
+        }
+        match self.buffer.pop() {
+            Some(byte) => Poll::Ready(Some(Ok(byte))),
+            None if self.closed.load(Ordering::Acquire) => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+
+    /// The consumer side of the buffer.
+    ///
+    /// There must be only one stream per buffer.
+    pub fn stream(&'static self) -> RxStream {
+        RxStream { buffer: self }
+    }
+}
+
+pub struct RxStream {
+    buffer: &'static RxBuffer,
+}
+
+impl Stream for RxStream {
+    type Item = Result<u8, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.buffer.poll_next(cx)
+    }
+}
+
+/// Moves the bytes received by `rx` to `buffer`.
+///
+/// To be called from the uart's interrupt handler.
+#[allow(dead_code)]
+pub fn receive<R>(rx: &mut R, buffer: &RxBuffer)
+where
+    R: embedded_hal::serial::Read<u8>,
+    R::Error: Into<Error>,
+{
+    loop {
+        match rx.read() {
+            Ok(byte) => buffer.push(byte),
+            Err(nb::Error::WouldBlock) => break,
+            Err(nb::Error::Other(e)) => buffer.report(e.into()),
+        }
+    }
+}
+
+pub struct TxBuffer {
+    buffer: RingBuffer<u8, TX_BUFFER_SIZE>,
+    /// task waiting for room in the buffer
+    waker: AtomicWaker,
+}
+
+impl TxBuffer {
+    pub const fn new() -> Self {
+        Self {
+            buffer: RingBuffer::new(),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// The producer side of the buffer.
+    ///
+    /// `start` is called whenever bytes were queued and must make sure the transmitter is running
+    /// (eg. by enabling the uart's transmit interrupt). There must be only one sink per buffer.
+    pub fn sink(&'static self, start: fn()) -> TxSink {
+        TxSink {
+            buffer: self,
+            start,
+        }
+    }
+}
+
+pub struct TxSink {
+    buffer: &'static TxBuffer,
+    start: fn(),
+}
+
+impl TxSink {
+    /// Queues `bytes` for transmission, waiting for room in the buffer as needed.
+    pub async fn write(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            future::poll_fn(|cx| {
+                self.buffer.waker.register(cx.waker());
+                if self.buffer.buffer.is_full() {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            })
+            .await;
+
+            // Checked before pushing, the buffer counts the bytes it rejects as dropped.
+            while !self.buffer.buffer.is_full() {
+                let (&byte, rest) = match bytes.split_first() {
+                    Some(split) => split,
+                    None => break,
+                };
+                self.buffer
+                    .buffer
+                    .push(byte)
+                    .unwrap_or_else(|_| unreachable!());
+                bytes = rest;
+            }
+            (self.start)();
+        }
+    }
+
+    /// Formats and queues a message, this is what `write!` and `writeln!` expand to.
+    ///
+    /// Fails if the message is longer than `TX_LINE_SIZE`.
+    pub async fn write_fmt(&mut self, args: core::fmt::Arguments<'_>) -> core::fmt::Result {
+        let mut line = ArrayString::<[u8; TX_LINE_SIZE]>::new();
+        line.write_fmt(args)?;
+        self.write(line.as_bytes()).await;
+        Ok(())
+    }
+}
+
+/// Moves the queued bytes from `buffer` to `tx` for as long as it accepts them.
+///
+/// To be called from the uart's interrupt handler. Returns `true` once the buffer is empty, in
+/// which case the transmit interrupt should be disabled until [`TxSink`] starts it again.
+#[allow(dead_code)]
+pub fn transmit<W>(tx: &mut W, buffer: &TxBuffer) -> bool
+where
+    W: embedded_hal::serial::Write<u8>,
+{
+    while let Some(byte) = buffer.buffer.peek() {
+        match tx.write(byte) {
+            Ok(()) => {
+                buffer.buffer.pop();
+                buffer.waker.wake();
+            }
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+
+    /// Stands for the uart's transmitter.
+    struct Uart(Vec<u8>);
+
+    impl embedded_hal::serial::Write<u8> for Uart {
+        type Error = ();
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+            self.0.push(byte);
+            Ok(())
+        }
+        fn flush(&mut self) -> nb::Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn waiting_for_room_drops_nothing() {
+        let buffer: &'static TxBuffer = Box::leak(Box::new(TxBuffer::new()));
+        let mut sink = buffer.sink(|| {});
+        let message: Vec<u8> = (0..3 * TX_BUFFER_SIZE).map(|i| i as u8).collect();
+        let mut uart = Uart(Vec::new());
+
+        let write = sink.write(&message);
+        pin_utils::pin_mut!(write);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        while write.poll_unpin(&mut cx).is_pending() {
+            assert!(buffer.buffer.is_full());
+            // The sink retries once the transmitter made room.
+            transmit(&mut uart, buffer);
+        }
+        transmit(&mut uart, buffer);
+
+        assert_eq!(uart.0, message);
+        assert_eq!(
+            buffer.buffer.stats(),
+            Stats {
+                high_water_mark: TX_BUFFER_SIZE,
+                dropped: 0,
+            }
+        );
+    }
+}
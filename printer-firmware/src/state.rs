@@ -0,0 +1,1505 @@
+//! Modal state of the machine.
+//!
+//! Every command is applied to the [`State`] which keeps track of the modes (units, positioning,
+//! plane, motion mode, …) that subsequent lines are interpreted in.
+//!
+//! The plane selects the axes arcs are drawn in, and the axis drilling cycles work along: the one
+//! normal to it.
+
+use crate::arc::{self, Arc};
+use crate::bezier::Bezier;
+use crate::config::{
+    SoftLimitPolicy, ARC_TOLERANCE, AXIS_NAMES, BEZIER_TOLERANCE, HOMING_ORDER, HOMING_REQUIRED,
+    KINEMATICS, PROBE_CLEARANCE, SOFT_LIMIT_POLICY, TRAVEL_MAX, TRAVEL_MIN,
+};
+use crate::gcode::processor::{ArcDirection, Command, MoveType};
+use crate::mesh::Mesh;
+
+/// Number of work coordinate systems (G54 to G59.3).
+pub const WORKSPACE_COUNT: usize = 9;
+
+/// How far past the travel limits rounding errors may take the tool, in mm.
+const LIMIT_TOLERANCE: f32 = 1e-3;
+
+/// Reasons for refusing a command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    Arc(arc::Error),
+    /// The command only works in another plane.
+    UnsupportedPlane(Plane),
+    /// The tool can't reach the end point of the move.
+    Unreachable(Workspace<f32>),
+    /// The parameter belongs to an axis normal to the active plane.
+    OffPlane {
+        parameter: char,
+        plane: Plane,
+    },
+    /// A parameter is missing, and wasn't given to a previous command either.
+    MissingParameter(char),
+    /// The bottom of a drilling cycle is above its R level.
+    InvalidDrillLevels {
+        r: f32,
+        bottom: f32,
+    },
+    /// The axis must be homed before being moved to an absolute position.
+    NotHomed(char),
+    /// Homing and probing are only supported with linear kinematics.
+    UnsupportedKinematics,
+    /// The leveling correction can't be enabled before a mesh was probed.
+    NoMesh,
+    /// The move would take the axis to `position`, in machine coordinates and mm, which is past
+    /// its travel limits.
+    OutOfLimits {
+        axis: char,
+        position: f32,
+    },
+}
+
+impl From<arc::Error> for Error {
+    fn from(e: arc::Error) -> Self {
+        Self::Arc(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Positioning {
+    Relative,
+    Absolute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MotionMode {
+    RapidLinear,
+    Linear,
+    ClockwiseControledArc,
+    CounterClockwiseControledArc,
+    BezierCubicSpline,
+    Drilling,
+    None,
+}
+
+/// Where the tool goes back to at the end of a drilling cycle, along the plane's normal axis.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetractMode {
+    /// The level the cycle started from, or the R level if it is higher.
+    Initial,
+    /// The R level.
+    R,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Millimeter,
+    Inch,
+}
+
+impl Unit {
+    /// Length of the unit, in mm.
+    fn length(self) -> f32 {
+        match self {
+            Unit::Millimeter => 1.,
+            Unit::Inch => 25.4,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Workspace<T> {
+    pub x: T,
+    pub y: T,
+    pub z: T,
+}
+
+impl<T: Copy> Workspace<T> {
+    pub fn map<U>(self, f: impl Fn(T) -> U) -> Workspace<U> {
+        Workspace {
+            x: f(self.x),
+            y: f(self.y),
+            z: f(self.z),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Plane {
+    XY,
+    YZ,
+    XZ,
+}
+
+#[derive(Debug, Clone)]
+pub struct State {
+    pub workspaces: [Workspace<f32>; WORKSPACE_COUNT],
+    pub current_workspace: u8,
+    pub plane: Plane,
+    pub unit: Unit,
+    pub motion_mode: MotionMode,
+    pub positioning: Positioning,
+    /// Set apart from the other axes' so that slicers may mix absolute moves and relative
+    /// extrusion
+    pub extruder_positioning: Positioning,
+    pub retract_mode: RetractMode,
+    /// R and bottom levels of the last drilling cycle, in machine coordinates
+    pub drill_levels: Option<(f32, f32)>,
+    pub axis_homed: Workspace<bool>,
+    /// Whether the travel limits apply
+    pub soft_limits: bool,
+    /// What happens to the moves going past the travel limits
+    pub soft_limit_policy: SoftLimitPolicy,
+    /// Last bed mesh probed
+    pub mesh: Option<Mesh>,
+    /// Whether moves are corrected by the bed mesh
+    pub leveling: bool,
+    /// Offsets set by G92, on top of the work coordinate system's ones
+    pub position_offset: Workspace<f32>,
+    /// Position of the tool, in machine coordinates
+    pub position: Workspace<f32>,
+    /// Position of the extruder
+    pub extruder_position: f32,
+    /// Feed rate in mm/min
+    pub feedrate: f32,
+    pub stepper_on: bool,
+    pub hotend_temperature_target: Option<f32>,
+    pub hotbed_temperature_target: Option<f32>,
+    /// Fan duty cycle, from 0.0 to 1.0.
+    pub fan_speed: Option<f32>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            workspaces: [Workspace::default(); WORKSPACE_COUNT],
+            current_workspace: 0,
+            plane: Plane::XY,
+            unit: Unit::Millimeter,
+            motion_mode: MotionMode::None,
+            positioning: Positioning::Absolute,
+            extruder_positioning: Positioning::Absolute,
+            retract_mode: RetractMode::Initial,
+            drill_levels: None,
+            axis_homed: Workspace::default(),
+            soft_limits: true,
+            soft_limit_policy: SOFT_LIMIT_POLICY,
+            mesh: None,
+            leveling: false,
+            position_offset: Workspace::default(),
+            position: Workspace::default(),
+            extruder_position: 0.,
+            feedrate: 0.,
+            stepper_on: false,
+            hotend_temperature_target: None,
+            hotbed_temperature_target: None,
+            fan_speed: None,
+        }
+    }
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Offsets of the work coordinates from the machine coordinates: the active work coordinate
+    /// system's plus the G92 ones.
+    pub fn offset(&self) -> Workspace<f32> {
+        let workspace = &self.workspaces[usize::from(self.current_workspace)];
+        Workspace {
+            x: workspace.x + self.position_offset.x,
+            y: workspace.y + self.position_offset.y,
+            z: workspace.z + self.position_offset.z,
+        }
+    }
+
+    /// Converts a length or speed from mm to the active unit, for reporting.
+    pub fn in_unit(&self, mm: f32) -> f32 {
+        mm / self.unit.length()
+    }
+
+    /// Converts the lengths and speeds carried by `cmd` from the active unit to mm.
+    fn to_millimeters(&self, cmd: &Command) -> Command {
+        let length = self.unit.length();
+        let mm = |v: Option<f32>| v.map(|v| v * length);
+        match *cmd {
+            Command::LinearMove {
+                move_type,
+                x,
+                y,
+                z,
+                e,
+            } => Command::LinearMove {
+                move_type,
+                x: mm(x),
+                y: mm(y),
+                z: mm(z),
+                e: mm(e),
+            },
+            Command::ArcMove {
+                direction,
+                x,
+                y,
+                z,
+                e,
+                i,
+                j,
+                k,
+                r,
+            } => Command::ArcMove {
+                direction,
+                x: mm(x),
+                y: mm(y),
+                z: mm(z),
+                e: mm(e),
+                i: mm(i),
+                j: mm(j),
+                k: mm(k),
+                r: mm(r),
+            },
+            Command::BezierMove {
+                x,
+                y,
+                e,
+                i,
+                j,
+                p,
+                q,
+            } => Command::BezierMove {
+                x: mm(x),
+                y: mm(y),
+                e: mm(e),
+                i: mm(i),
+                j: mm(j),
+                p: mm(p),
+                q: mm(q),
+            },
+            Command::Drill {
+                x,
+                y,
+                z,
+                r,
+                retract,
+            } => Command::Drill {
+                x: mm(x),
+                y: mm(y),
+                z: mm(z),
+                r: mm(r),
+                retract: mm(retract),
+            },
+            Command::FeedRate(f) => Command::FeedRate(f * length),
+            Command::SetPosition { x, y, z, e } => Command::SetPosition {
+                x: mm(x),
+                y: mm(y),
+                z: mm(z),
+                e: mm(e),
+            },
+            Command::SetWorkspaceOffset { workspace, x, y, z } => Command::SetWorkspaceOffset {
+                workspace,
+                x: mm(x),
+                y: mm(y),
+                z: mm(z),
+            },
+            Command::SetWorkspacePosition { workspace, x, y, z } => Command::SetWorkspacePosition {
+                workspace,
+                x: mm(x),
+                y: mm(y),
+                z: mm(z),
+            },
+            other => other,
+        }
+    }
+
+    /// Position of the tool in work coordinates.
+    pub fn work_position(&self) -> Workspace<f32> {
+        let offset = self.offset();
+        Workspace {
+            x: self.position.x - offset.x,
+            y: self.position.y - offset.y,
+            z: self.position.z - offset.z,
+        }
+    }
+
+    /// End point of a move, as the tool position in machine coordinates and the extruder position.
+    fn target(
+        &self,
+        x: Option<f32>,
+        y: Option<f32>,
+        z: Option<f32>,
+        e: Option<f32>,
+    ) -> (Workspace<f32>, f32) {
+        let target = |positioning: Positioning, current: f32, offset: f32, v: Option<f32>| match v {
+            Some(v) if positioning == Positioning::Relative => current + v,
+            Some(v) => v + offset,
+            None => current,
+        };
+        let (positioning, offset) = (self.positioning, self.offset());
+        (
+            Workspace {
+                x: target(positioning, self.position.x, offset.x, x),
+                y: target(positioning, self.position.y, offset.y, y),
+                z: target(positioning, self.position.z, offset.z, z),
+            },
+            target(self.extruder_positioning, self.extruder_position, 0., e),
+        )
+    }
+
+    fn reachable(&self, position: Workspace<f32>) -> Result<(), Error> {
+        match KINEMATICS.inverse(position) {
+            Some(_) => Ok(()),
+            None => Err(Error::Unreachable(position)),
+        }
+    }
+
+    /// Resolves the levels of a drilling cycle to machine coordinates.
+    fn drill(
+        &self,
+        x: Option<f32>,
+        y: Option<f32>,
+        z: Option<f32>,
+        r: Option<f32>,
+    ) -> Result<Command, Error> {
+        let (_, _, normal) = arc::axes(self.plane);
+        let mut words = [x, y, z];
+        let depth = words[normal].take();
+        let [x, y, z] = words;
+        let (hole, _) = self.target(x, y, z, None);
+
+        let initial = [self.position.x, self.position.y, self.position.z][normal];
+        let offset = self.offset();
+        let offset = [offset.x, offset.y, offset.z][normal];
+        let relative = self.positioning == Positioning::Relative;
+        let previous = self.drill_levels;
+        // When relative, R is measured from the initial level and the bottom from R.
+        let r = match r {
+            Some(r) if relative => initial + r,
+            Some(r) => r + offset,
+            None => previous.ok_or(Error::MissingParameter('r'))?.0,
+        };
+        let bottom = match depth {
+            Some(depth) if relative => r + depth,
+            Some(depth) => depth + offset,
+            None => {
+                previous
+                    .ok_or(Error::MissingParameter(['x', 'y', 'z'][normal]))?
+                    .1
+            }
+        };
+        if bottom > r {
+            return Err(Error::InvalidDrillLevels { r, bottom });
+        }
+
+        let mut hole = [hole.x, hole.y, hole.z];
+        hole[normal] = bottom;
+        let [x, y, z] = hole;
+        self.reachable(Workspace { x, y, z })?;
+        self.check_limits(Workspace { x, y, z })?;
+        let retract = match self.retract_mode {
+            RetractMode::Initial => initial.max(r),
+            RetractMode::R => r,
+        };
+        let mut top = hole;
+        top[normal] = retract;
+        let [tx, ty, tz] = top;
+        self.check_limits(Workspace {
+            x: tx,
+            y: ty,
+            z: tz,
+        })?;
+        Ok(Command::Drill {
+            x: Some(x),
+            y: Some(y),
+            z: Some(z),
+            r: Some(r),
+            retract: Some(retract),
+        })
+    }
+
+    /// Moves the tool to the end point of a move.
+    fn move_to(&mut self, x: Option<f32>, y: Option<f32>, z: Option<f32>, e: Option<f32>) {
+        let (position, extruder_position) = self.target(x, y, z, e);
+        self.position = position;
+        self.extruder_position = extruder_position;
+    }
+
+    /// The axes given a value by an absolute move.
+    fn absolute(&self, words: [Option<f32>; 3]) -> [bool; 3] {
+        let absolute = self.positioning == Positioning::Absolute;
+        let [x, y, z] = words;
+        [
+            absolute && x.is_some(),
+            absolute && y.is_some(),
+            absolute && z.is_some(),
+        ]
+    }
+
+    /// Checks that the given axes were homed.
+    fn check_homed(&self, axes: [bool; 3]) -> Result<(), Error> {
+        if !HOMING_REQUIRED {
+            return Ok(());
+        }
+        let homed = [self.axis_homed.x, self.axis_homed.y, self.axis_homed.z];
+        match (0..3).find(|axis| axes[*axis] && !homed[*axis]) {
+            Some(axis) => Err(Error::NotHomed(AXIS_NAMES[axis])),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that `position` is within the travel limits, on the homed axes.
+    fn check_limits(&self, position: Workspace<f32>) -> Result<(), Error> {
+        if !self.soft_limits {
+            return Ok(());
+        }
+        let homed = [self.axis_homed.x, self.axis_homed.y, self.axis_homed.z];
+        let position = [position.x, position.y, position.z];
+        let out = |axis: usize| {
+            position[axis] < TRAVEL_MIN[axis] - LIMIT_TOLERANCE
+                || position[axis] > TRAVEL_MAX[axis] + LIMIT_TOLERANCE
+        };
+        match (0..3).find(|axis| homed[*axis] && out(*axis)) {
+            Some(axis) => Err(Error::OutOfLimits {
+                axis: AXIS_NAMES[axis],
+                position: position[axis],
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// The X, Y and Z words of a straight move stopping at the travel limits, on the homed axes.
+    fn clip(&self, x: Option<f32>, y: Option<f32>, z: Option<f32>) -> [Option<f32>; 3] {
+        let mut words = [x, y, z];
+        if !self.soft_limits {
+            return words;
+        }
+        let homed = [self.axis_homed.x, self.axis_homed.y, self.axis_homed.z];
+        let (end, _) = self.target(x, y, z, None);
+        let end = [end.x, end.y, end.z];
+        let current = [self.position.x, self.position.y, self.position.z];
+        let offset = self.offset();
+        let offset = [offset.x, offset.y, offset.z];
+        for axis in (0..3).filter(|axis| homed[*axis]) {
+            let clipped = end[axis].max(TRAVEL_MIN[axis]).min(TRAVEL_MAX[axis]);
+            if clipped != end[axis] {
+                words[axis] = Some(match self.positioning {
+                    Positioning::Relative => clipped - current[axis],
+                    Positioning::Absolute => clipped - offset[axis],
+                });
+            }
+        }
+        words
+    }
+
+    /// Flags the given axes as not homed.
+    pub fn unhome(&mut self, x: bool, y: bool, z: bool) {
+        self.axis_homed.x &= !x;
+        self.axis_homed.y &= !y;
+        self.axis_homed.z &= !z;
+    }
+
+    /// Unflags the axes of a homing cycle that failed on the `failed` axis: that one and the ones
+    /// that were to be homed after it. The axes homed before it keep their new position.
+    pub fn homing_failed(&mut self, x: bool, y: bool, z: bool, failed: char) {
+        let requested = [x, y, z];
+        let mut unhomed = [false; 3];
+        let left = HOMING_ORDER
+            .iter()
+            .skip_while(|axis| AXIS_NAMES[**axis] != failed);
+        for &axis in left {
+            unhomed[axis] = requested[axis];
+        }
+        let [x, y, z] = unhomed;
+        self.unhome(x, y, z);
+    }
+
+    /// Stores the mesh probed by G29, whose correction the motion applies from then on.
+    pub fn set_mesh(&mut self, mesh: Mesh) {
+        self.mesh = Some(mesh);
+        self.leveling = true;
+    }
+
+    /// Updates the state with the effects of `cmd`.
+    ///
+    /// Returns the command to be queued for the motion, if any, with its coordinates resolved to
+    /// absolute positions in mm and the center of arcs given as offsets from their start point. The
+    /// state is left untouched if the command is refused.
+    ///
+    /// This is where lengths and speeds are converted from the active unit: the state and the
+    /// motion only deal with millimeters.
+    pub fn apply(&mut self, cmd: &Command) -> Result<Option<Command>, Error> {
+        let cmd = &self.to_millimeters(cmd);
+        // Moves are checked before anything is updated.
+        match *cmd {
+            Command::LinearMove { x, y, z, .. } | Command::ArcMove { x, y, z, .. } => {
+                self.check_homed(self.absolute([x, y, z]))?
+            }
+            Command::BezierMove { x, y, .. } => self.check_homed(self.absolute([x, y, None]))?,
+            Command::Drill { x, y, z, .. } => {
+                // The levels of the normal axis are absolute too.
+                let mut words = [x, y, z];
+                words[arc::axes(self.plane).2] = Some(0.);
+                self.check_homed(self.absolute(words))?
+            }
+            Command::Home { .. } | Command::ProbeMesh if !KINEMATICS.is_linear() => {
+                return Err(Error::UnsupportedKinematics)
+            }
+            // The probe is taken all over the bed.
+            Command::ProbeMesh => self.check_homed([true; 3])?,
+            Command::SetLeveling {
+                enabled: Some(true),
+                ..
+            } if self.mesh.is_none() => return Err(Error::NoMesh),
+            _ => {}
+        }
+        let clipped = match *cmd {
+            Command::LinearMove {
+                move_type,
+                x,
+                y,
+                z,
+                e,
+            } if self.soft_limit_policy == SoftLimitPolicy::Clip => {
+                let [x, y, z] = self.clip(x, y, z);
+                Some(Command::LinearMove {
+                    move_type,
+                    x,
+                    y,
+                    z,
+                    e,
+                })
+            }
+            _ => None,
+        };
+        let cmd = clipped.as_ref().unwrap_or(cmd);
+        let arc_center = match *cmd {
+            Command::LinearMove { x, y, z, e, .. } => {
+                let (end, _) = self.target(x, y, z, e);
+                self.reachable(end)?;
+                self.check_limits(end)?;
+                None
+            }
+            Command::ArcMove {
+                direction,
+                x,
+                y,
+                z,
+                e,
+                i,
+                j,
+                k,
+                r,
+            } => {
+                let (_, _, normal) = arc::axes(self.plane);
+                if [i, j, k][normal].is_some() {
+                    return Err(Error::OffPlane {
+                        parameter: ['i', 'j', 'k'][normal],
+                        plane: self.plane,
+                    });
+                }
+                let (end, _) = self.target(x, y, z, e);
+                self.reachable(end)?;
+                let start = [self.position.x, self.position.y, self.position.z];
+                let end = [end.x, end.y, end.z];
+                let center = arc::center(self.plane, direction, start, end, [i, j, k], r)?;
+                if self.soft_limits {
+                    let arc = Arc::new(self.plane, direction, start, end, center);
+                    let count = arc.chords(ARC_TOLERANCE);
+                    for n in 1..=count {
+                        let [x, y, z] = arc.point(n, count);
+                        self.check_limits(Workspace { x, y, z })?;
+                    }
+                }
+                Some(center)
+            }
+            Command::BezierMove { .. } if self.plane != Plane::XY => {
+                return Err(Error::UnsupportedPlane(self.plane))
+            }
+            Command::BezierMove {
+                x,
+                y,
+                e,
+                i,
+                j,
+                p,
+                q,
+            } => {
+                let (end, _) = self.target(x, y, None, e);
+                self.reachable(end)?;
+                if self.soft_limits {
+                    let offset = |o: Option<f32>| o.unwrap_or(0.);
+                    let curve = Bezier::new(
+                        [self.position.x, self.position.y],
+                        [end.x, end.y],
+                        [offset(i), offset(j)],
+                        [offset(p), offset(q)],
+                    );
+                    for [x, y] in curve.flatten(BEZIER_TOLERANCE) {
+                        self.check_limits(Workspace { x, y, z: end.z })?;
+                    }
+                }
+                None
+            }
+            _ => None,
+        };
+        // Drilling cycles are resolved in one go, from their sticky levels.
+        let drill = match *cmd {
+            Command::Drill { x, y, z, r, .. } => Some(self.drill(x, y, z, r)?),
+            _ => None,
+        };
+        let cmd = drill.as_ref().unwrap_or(cmd);
+        self.update(cmd);
+
+        let (x, y, z, e) = (
+            Some(self.position.x),
+            Some(self.position.y),
+            Some(self.position.z),
+            Some(self.extruder_position),
+        );
+        Ok(match *cmd {
+            Command::LinearMove { move_type, .. } => Some(Command::LinearMove {
+                move_type,
+                x,
+                y,
+                z,
+                e,
+            }),
+            Command::ArcMove { direction, .. } => {
+                let [i, j, k] = arc_center.unwrap_or_else(|| unreachable!());
+                Some(Command::ArcMove {
+                    direction,
+                    x,
+                    y,
+                    z,
+                    e,
+                    i: Some(i),
+                    j: Some(j),
+                    k: Some(k),
+                    r: None,
+                })
+            }
+            Command::BezierMove { i, j, p, q, .. } => {
+                let offset = |o: Option<f32>| Some(o.unwrap_or(0.));
+                Some(Command::BezierMove {
+                    x,
+                    y,
+                    e,
+                    i: offset(i),
+                    j: offset(j),
+                    p: offset(p),
+                    q: offset(q),
+                })
+            }
+            // Only the extruder is moved to its new position, the tool gets new offsets instead.
+            Command::SetPosition { e: Some(_), .. } => Some(Command::SetPosition {
+                x: None,
+                y: None,
+                z: None,
+                e,
+            }),
+            Command::FeedRate(_)
+            | Command::Drill { .. }
+            | Command::SetPlane(_)
+            | Command::Dwell(_)
+            | Command::Home { .. }
+            | Command::ProbeMesh
+            | Command::SetLeveling {
+                enabled: Some(_), ..
+            }
+            | Command::EnableSteppers
+            | Command::DisableSteppers => Some(*cmd),
+            _ => None,
+        })
+    }
+
+    fn update(&mut self, cmd: &Command) {
+        match *cmd {
+            Command::LinearMove {
+                move_type,
+                x,
+                y,
+                z,
+                e,
+            } => {
+                self.motion_mode = match move_type {
+                    MoveType::Quick => MotionMode::RapidLinear,
+                    MoveType::Linear => MotionMode::Linear,
+                };
+                self.move_to(x, y, z, e);
+            }
+            Command::ArcMove {
+                direction,
+                x,
+                y,
+                z,
+                e,
+                ..
+            } => {
+                self.motion_mode = match direction {
+                    ArcDirection::Clockwise => MotionMode::ClockwiseControledArc,
+                    ArcDirection::CounterClockwise => MotionMode::CounterClockwiseControledArc,
+                };
+                self.move_to(x, y, z, e);
+            }
+            Command::BezierMove { x, y, e, .. } => {
+                self.motion_mode = MotionMode::BezierCubicSpline;
+                self.move_to(x, y, None, e);
+            }
+            Command::Drill {
+                x,
+                y,
+                z,
+                r,
+                retract,
+            } => {
+                // Only ever given resolved, in machine coordinates.
+                self.motion_mode = MotionMode::Drilling;
+                let (_, _, normal) = arc::axes(self.plane);
+                let current = self.position;
+                let mut position = [
+                    x.unwrap_or(current.x),
+                    y.unwrap_or(current.y),
+                    z.unwrap_or(current.z),
+                ];
+                self.drill_levels = r.map(|r| (r, position[normal]));
+                position[normal] = retract.unwrap_or(position[normal]);
+                let [x, y, z] = position;
+                self.position = Workspace { x, y, z };
+            }
+            Command::FeedRate(f) => self.feedrate = f,
+            Command::Home { x, y, z } => {
+                // The intake unflags the axes left unhomed if the cycle fails, the motion puts
+                // them at 0 all the same.
+                if x {
+                    self.axis_homed.x = true;
+                    self.position.x = 0.;
+                }
+                if y {
+                    self.axis_homed.y = true;
+                    self.position.y = 0.;
+                }
+                if z {
+                    self.axis_homed.z = true;
+                    self.position.z = 0.;
+                }
+                self.leveling = false;
+            }
+            Command::ProbeMesh => {
+                // The intake stores the mesh once probed, the tool ends above where it started.
+                self.position.z = PROBE_CLEARANCE;
+                self.leveling = false;
+            }
+            Command::SetPosition { x, y, z, e } => {
+                // The G92 offsets are chosen for the current position to be the given one.
+                let workspace = self.workspaces[usize::from(self.current_workspace)];
+                let offset = |position: f32, workspace: f32, v: Option<f32>, current: f32| {
+                    v.map_or(current, |v| position - workspace - v)
+                };
+                self.position_offset = Workspace {
+                    x: offset(self.position.x, workspace.x, x, self.position_offset.x),
+                    y: offset(self.position.y, workspace.y, y, self.position_offset.y),
+                    z: offset(self.position.z, workspace.z, z, self.position_offset.z),
+                };
+                self.extruder_position = e.unwrap_or(self.extruder_position);
+            }
+            Command::ResetPositionOffset => self.position_offset = Workspace::default(),
+            Command::SetWorkspaceOffset { workspace, x, y, z } => {
+                let workspace =
+                    &mut self.workspaces[usize::from(workspace.unwrap_or(self.current_workspace))];
+                workspace.x = x.unwrap_or(workspace.x);
+                workspace.y = y.unwrap_or(workspace.y);
+                workspace.z = z.unwrap_or(workspace.z);
+            }
+            Command::SetWorkspacePosition { workspace, x, y, z } => {
+                let (position, position_offset) = (self.position, self.position_offset);
+                let workspace =
+                    &mut self.workspaces[usize::from(workspace.unwrap_or(self.current_workspace))];
+                let offset = |position: f32, position_offset: f32, v: Option<f32>, current: f32| {
+                    v.map_or(current, |v| position - position_offset - v)
+                };
+                workspace.x = offset(position.x, position_offset.x, x, workspace.x);
+                workspace.y = offset(position.y, position_offset.y, y, workspace.y);
+                workspace.z = offset(position.z, position_offset.z, z, workspace.z);
+            }
+            Command::CancelMotionMode => self.motion_mode = MotionMode::None,
+            Command::SetPlane(plane) => self.plane = plane,
+            Command::SetUnit(unit) => self.unit = unit,
+            Command::SetPositioning(positioning) => self.positioning = positioning,
+            Command::SetExtruderPositioning(positioning) => self.extruder_positioning = positioning,
+            Command::SetRetractMode(mode) => self.retract_mode = mode,
+            Command::SetSoftLimits(Some(enabled)) => self.soft_limits = enabled,
+            Command::SetLeveling {
+                enabled: Some(enabled),
+                ..
+            } => self.leveling = enabled,
+            Command::SelectWorkspace(idx) => self.current_workspace = idx,
+            Command::EnableSteppers => self.stepper_on = true,
+            Command::DisableSteppers => self.stepper_on = false,
+            Command::SetHotendTemperature { target, .. } => {
+                self.hotend_temperature_target = if target > 0. { Some(target) } else { None }
+            }
+            Command::SetBedTemperature { target, .. } => {
+                self.hotbed_temperature_target = if target > 0. { Some(target) } else { None }
+            }
+            Command::SetFanSpeed(speed) => {
+                self.fan_speed = if speed > 0. { Some(speed) } else { None }
+            }
+            Command::Dwell(_)
+            | Command::ReportTemperatures
+            | Command::ReportPosition
+            | Command::ReportWorkspace(_)
+            | Command::SetSoftLimits(None)
+            | Command::SetLeveling { enabled: None, .. }
+            | Command::ReportFirmware
+            | Command::SetLineNumber(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linear(x: Option<f32>, y: Option<f32>, z: Option<f32>) -> Command {
+        Command::LinearMove {
+            move_type: MoveType::Linear,
+            x,
+            y,
+            z,
+            e: None,
+        }
+    }
+
+    /// A state whose axes were all homed, with the tool at `position`.
+    fn homed(x: f32, y: f32, z: f32) -> State {
+        let mut state = State::new();
+        state.axis_homed = Workspace {
+            x: true,
+            y: true,
+            z: true,
+        };
+        state.position = Workspace { x, y, z };
+        state
+    }
+
+    #[test]
+    fn positioning() {
+        let mut state = homed(10., 20., 30.);
+        assert_eq!(
+            state.apply(&linear(Some(5.), None, Some(1.))),
+            Ok(Some(Command::LinearMove {
+                move_type: MoveType::Linear,
+                x: Some(5.),
+                y: Some(20.),
+                z: Some(1.),
+                e: Some(0.),
+            }))
+        );
+        assert_eq!(state.motion_mode, MotionMode::Linear);
+
+        state
+            .apply(&Command::SetPositioning(Positioning::Relative))
+            .unwrap();
+        state.apply(&linear(Some(5.), Some(-5.), None)).unwrap();
+        assert_eq!(
+            state.position,
+            Workspace {
+                x: 10.,
+                y: 15.,
+                z: 1.
+            }
+        );
+    }
+
+    #[test]
+    fn modal_state() {
+        let mut state = State::new();
+        let commands = [
+            Command::SetPlane(Plane::XZ),
+            Command::SetPositioning(Positioning::Relative),
+            Command::LinearMove {
+                move_type: MoveType::Quick,
+                x: Some(1.),
+                y: None,
+                z: None,
+                e: None,
+            },
+            Command::EnableSteppers,
+            Command::SetHotendTemperature {
+                target: 200.,
+                wait: false,
+            },
+            Command::SetBedTemperature {
+                target: 60.,
+                wait: true,
+            },
+            Command::SetFanSpeed(0.5),
+        ];
+        for cmd in &commands {
+            state.apply(cmd).unwrap();
+        }
+        assert_eq!(state.plane, Plane::XZ);
+        assert_eq!(state.positioning, Positioning::Relative);
+        assert_eq!(state.motion_mode, MotionMode::RapidLinear);
+        assert!(state.stepper_on);
+        assert_eq!(state.hotend_temperature_target, Some(200.));
+        assert_eq!(state.hotbed_temperature_target, Some(60.));
+        assert_eq!(state.fan_speed, Some(0.5));
+
+        // Zero turns the heaters and the fan off.
+        let commands = [
+            Command::CancelMotionMode,
+            Command::DisableSteppers,
+            Command::SetHotendTemperature {
+                target: 0.,
+                wait: false,
+            },
+            Command::SetBedTemperature {
+                target: 0.,
+                wait: false,
+            },
+            Command::SetFanSpeed(0.),
+        ];
+        for cmd in &commands {
+            state.apply(cmd).unwrap();
+        }
+        assert_eq!(state.motion_mode, MotionMode::None);
+        assert!(!state.stepper_on);
+        assert_eq!(state.hotend_temperature_target, None);
+        assert_eq!(state.hotbed_temperature_target, None);
+        assert_eq!(state.fan_speed, None);
+    }
+
+    fn drill(words: [Option<f32>; 3], r: Option<f32>) -> Command {
+        let [x, y, z] = words;
+        Command::Drill {
+            x,
+            y,
+            z,
+            r,
+            retract: None,
+        }
+    }
+
+    /// `hole` in the plane and `level` along its normal, as X, Y and Z.
+    fn in_plane(plane: Plane, hole: [f32; 2], level: f32) -> [f32; 3] {
+        let (a, b, normal) = arc::axes(plane);
+        let mut position = [0.; 3];
+        position[a] = hole[0];
+        position[b] = hole[1];
+        position[normal] = level;
+        position
+    }
+
+    #[test]
+    fn drills_along_the_normal_of_the_plane() {
+        for plane in &[Plane::XY, Plane::XZ, Plane::YZ] {
+            let [x, y, z] = in_plane(*plane, [0., 0.], 20.);
+            let mut state = homed(x, y, z);
+            state.apply(&Command::SetPlane(*plane)).unwrap();
+
+            let [x, y, z] = in_plane(*plane, [30., 40.], 2.);
+            let resolved = state.apply(&drill([Some(x), Some(y), Some(z)], Some(10.)));
+            assert_eq!(
+                resolved,
+                Ok(Some(Command::Drill {
+                    x: Some(x),
+                    y: Some(y),
+                    z: Some(z),
+                    r: Some(10.),
+                    retract: Some(20.),
+                })),
+                "{:?}",
+                plane
+            );
+            assert_eq!(state.motion_mode, MotionMode::Drilling);
+            let [x, y, z] = in_plane(*plane, [30., 40.], 20.);
+            assert_eq!(state.position, Workspace { x, y, z }, "{:?}", plane);
+
+            // The levels are sticky, only the hole changes.
+            let [x, y, z] = in_plane(*plane, [50., 60.], 0.);
+            let mut hole = [Some(x), Some(y), Some(z)];
+            hole[arc::axes(*plane).2] = None;
+            state.apply(&drill(hole, None)).unwrap();
+            let [x, y, z] = in_plane(*plane, [50., 60.], 20.);
+            assert_eq!(state.position, Workspace { x, y, z }, "{:?}", plane);
+        }
+    }
+
+    #[test]
+    fn retract_levels() {
+        let mut state = homed(0., 0., 20.);
+        let hole = [Some(30.), Some(40.), Some(2.)];
+        // G98 goes back to the initial level, or to R if it is higher.
+        state.apply(&drill(hole, Some(10.))).unwrap();
+        assert_eq!(state.position.z, 20.);
+        state.apply(&drill(hole, Some(25.))).unwrap();
+        assert_eq!(state.position.z, 25.);
+
+        state
+            .apply(&Command::SetRetractMode(RetractMode::R))
+            .unwrap();
+        state.apply(&drill(hole, Some(10.))).unwrap();
+        assert_eq!(state.position.z, 10.);
+        assert_eq!(state.drill_levels, Some((10., 2.)));
+    }
+
+    #[test]
+    fn relative_drill_levels() {
+        let mut state = homed(0., 0., 20.);
+        state
+            .apply(&Command::SetPositioning(Positioning::Relative))
+            .unwrap();
+        // R is measured from the initial level, the bottom from R.
+        assert_eq!(
+            state.apply(&drill([Some(5.), None, Some(-8.)], Some(-5.))),
+            Ok(Some(Command::Drill {
+                x: Some(5.),
+                y: Some(0.),
+                z: Some(7.),
+                r: Some(15.),
+                retract: Some(20.),
+            }))
+        );
+    }
+
+    #[test]
+    fn invalid_drill_levels() {
+        let mut state = homed(0., 0., 20.);
+        assert_eq!(
+            state.apply(&drill([None, None, Some(2.)], None)),
+            Err(Error::MissingParameter('r'))
+        );
+        assert_eq!(
+            state.apply(&drill([None, None, None], Some(5.))),
+            Err(Error::MissingParameter('z'))
+        );
+        assert_eq!(
+            state.apply(&drill([None, None, Some(8.)], Some(5.))),
+            Err(Error::InvalidDrillLevels { r: 5., bottom: 8. })
+        );
+        state.apply(&Command::SetPlane(Plane::XZ)).unwrap();
+        assert_eq!(
+            state.apply(&drill([None, None, None], Some(5.))),
+            Err(Error::MissingParameter('y'))
+        );
+        assert_eq!(
+            state.position,
+            Workspace {
+                x: 0.,
+                y: 0.,
+                z: 20.
+            }
+        );
+    }
+
+    #[test]
+    fn offsets() {
+        let mut state = homed(10., 10., 10.);
+        state
+            .apply(&Command::SetWorkspaceOffset {
+                workspace: Some(1),
+                x: Some(50.),
+                y: Some(60.),
+                z: None,
+            })
+            .unwrap();
+        // The offsets only apply once their work coordinate system is selected.
+        assert_eq!(state.offset(), Workspace::default());
+        state.apply(&Command::SelectWorkspace(1)).unwrap();
+        assert_eq!(
+            state.work_position(),
+            Workspace {
+                x: -40.,
+                y: -50.,
+                z: 10.
+            }
+        );
+
+        // G92 makes the current position the given one, on top of the work offsets.
+        state
+            .apply(&Command::SetPosition {
+                x: Some(0.),
+                y: None,
+                z: Some(5.),
+                e: None,
+            })
+            .unwrap();
+        assert_eq!(
+            state.work_position(),
+            Workspace {
+                x: 0.,
+                y: -50.,
+                z: 5.
+            }
+        );
+        assert_eq!(
+            state.position,
+            Workspace {
+                x: 10.,
+                y: 10.,
+                z: 10.
+            }
+        );
+
+        state
+            .apply(&linear(Some(1.), Some(-40.), Some(0.)))
+            .unwrap();
+        assert_eq!(
+            state.position,
+            Workspace {
+                x: 11.,
+                y: 20.,
+                z: 5.
+            }
+        );
+
+        state.apply(&Command::ResetPositionOffset).unwrap();
+        assert_eq!(
+            state.offset(),
+            Workspace {
+                x: 50.,
+                y: 60.,
+                z: 0.
+            }
+        );
+    }
+
+    #[test]
+    fn workspace_position() {
+        let mut state = homed(10., 20., 30.);
+        state
+            .apply(&Command::SetWorkspacePosition {
+                workspace: None,
+                x: Some(0.),
+                y: Some(5.),
+                z: None,
+            })
+            .unwrap();
+        assert_eq!(
+            state.workspaces[0],
+            Workspace {
+                x: 10.,
+                y: 15.,
+                z: 0.
+            }
+        );
+        assert_eq!(
+            state.work_position(),
+            Workspace {
+                x: 0.,
+                y: 5.,
+                z: 30.
+            }
+        );
+    }
+
+    #[test]
+    fn units() {
+        let mut state = homed(0., 0., 0.);
+        state.apply(&Command::SetUnit(Unit::Inch)).unwrap();
+        state.apply(&linear(Some(1.), Some(2.), None)).unwrap();
+        assert_eq!(
+            state.position,
+            Workspace {
+                x: 25.4,
+                y: 50.8,
+                z: 0.
+            }
+        );
+        state.apply(&Command::FeedRate(10.)).unwrap();
+        assert_eq!(state.feedrate, 254.);
+        assert_eq!(state.in_unit(50.8), 2.);
+
+        state.apply(&Command::SetUnit(Unit::Millimeter)).unwrap();
+        state.apply(&linear(Some(1.), None, None)).unwrap();
+        assert_eq!(state.position.x, 1.);
+    }
+
+    fn arc(end: [f32; 3], offsets: [Option<f32>; 3], r: Option<f32>) -> Command {
+        let [x, y, z] = end;
+        let [i, j, k] = offsets;
+        Command::ArcMove {
+            direction: ArcDirection::Clockwise,
+            x: Some(x),
+            y: Some(y),
+            z: Some(z),
+            e: None,
+            i,
+            j,
+            k,
+            r,
+        }
+    }
+
+    #[test]
+    fn arcs_in_each_plane() {
+        for plane in &[Plane::XY, Plane::XZ, Plane::YZ] {
+            let [x, y, z] = in_plane(*plane, [50., 50.], 10.);
+            let mut state = homed(x, y, z);
+            state.apply(&Command::SetPlane(*plane)).unwrap();
+
+            // A half turn rising along the normal, then back to the start with a radius.
+            let [x, y, z] = in_plane(*plane, [70., 50.], 15.);
+            let [i, j, k] = in_plane(*plane, [10., 0.], 0.);
+            let mut offsets = [Some(i), Some(j), Some(k)];
+            offsets[arc::axes(*plane).2] = None;
+            let expected = |end: [f32; 3], center: [f32; 3]| {
+                let ([x, y, z], [i, j, k]) = (end, center);
+                Ok(Some(Command::ArcMove {
+                    direction: ArcDirection::Clockwise,
+                    x: Some(x),
+                    y: Some(y),
+                    z: Some(z),
+                    e: Some(0.),
+                    i: Some(i),
+                    j: Some(j),
+                    k: Some(k),
+                    r: None,
+                }))
+            };
+            assert_eq!(
+                state.apply(&arc([x, y, z], offsets, None)),
+                expected([x, y, z], [i, j, k]),
+                "{:?}",
+                plane
+            );
+            assert_eq!(state.motion_mode, MotionMode::ClockwiseControledArc);
+            assert_eq!(state.position, Workspace { x, y, z }, "{:?}", plane);
+
+            let start = in_plane(*plane, [50., 50.], 15.);
+            assert_eq!(
+                state.apply(&arc(start, [None; 3], Some(10.))),
+                expected(start, in_plane(*plane, [-10., 0.], 0.)),
+                "{:?}",
+                plane
+            );
+        }
+    }
+
+    #[test]
+    fn arcs_refuse_the_offset_along_the_normal() {
+        let mut state = homed(50., 50., 10.);
+        let before = state.clone();
+        assert_eq!(
+            state.apply(&arc([70., 50., 10.], [Some(10.), None, Some(0.)], None)),
+            Err(Error::OffPlane {
+                parameter: 'k',
+                plane: Plane::XY,
+            })
+        );
+        assert_eq!(state.position, before.position);
+
+        state.apply(&Command::SetPlane(Plane::YZ)).unwrap();
+        assert_eq!(
+            state.apply(&arc([50., 70., 10.], [Some(0.), Some(10.), None], None)),
+            Err(Error::OffPlane {
+                parameter: 'i',
+                plane: Plane::YZ,
+            })
+        );
+        assert_eq!(state.position, before.position);
+    }
+
+    #[test]
+    fn bezier_curves_only_work_in_xy() {
+        let curve = Command::BezierMove {
+            x: Some(20.),
+            y: Some(20.),
+            e: None,
+            i: Some(5.),
+            j: None,
+            p: None,
+            q: Some(-5.),
+        };
+        for plane in &[Plane::XZ, Plane::YZ] {
+            let mut state = homed(10., 10., 10.);
+            state.apply(&Command::SetPlane(*plane)).unwrap();
+            assert_eq!(state.apply(&curve), Err(Error::UnsupportedPlane(*plane)));
+            assert_eq!(state.position.x, 10.);
+        }
+
+        let mut state = homed(10., 10., 10.);
+        state.apply(&curve).unwrap();
+        assert_eq!(state.position.x, 20.);
+    }
+
+    #[test]
+    fn extruder_positioning() {
+        let mut state = homed(0., 0., 0.);
+        state
+            .apply(&Command::SetExtruderPositioning(Positioning::Relative))
+            .unwrap();
+        let extrude = Command::LinearMove {
+            move_type: MoveType::Linear,
+            x: Some(1.),
+            y: None,
+            z: None,
+            e: Some(2.),
+        };
+        state.apply(&extrude).unwrap();
+        state.apply(&extrude).unwrap();
+        assert_eq!(state.position.x, 1.);
+        assert_eq!(state.extruder_position, 4.);
+    }
+
+    #[test]
+    fn absolute_moves_need_homing() {
+        let mut state = State::new();
+        assert_eq!(
+            state.apply(&linear(Some(1.), None, None)),
+            Err(Error::NotHomed('X'))
+        );
+        // Relative moves are allowed, to get the tool off an endstop.
+        state
+            .apply(&Command::SetPositioning(Positioning::Relative))
+            .unwrap();
+        state.apply(&linear(None, None, Some(2.))).unwrap();
+        assert_eq!(state.position.z, 2.);
+
+        state
+            .apply(&Command::SetPositioning(Positioning::Absolute))
+            .unwrap();
+        state
+            .apply(&Command::Home {
+                x: true,
+                y: false,
+                z: false,
+            })
+            .unwrap();
+        state.apply(&linear(Some(1.), None, None)).unwrap();
+        assert_eq!(
+            state.apply(&linear(Some(1.), Some(1.), None)),
+            Err(Error::NotHomed('Y'))
+        );
+    }
+
+    #[test]
+    fn failed_homing_keeps_the_axes_homed_before() {
+        // Z, X then Y.
+        let mut state = homed(10., 10., 10.);
+        state.homing_failed(true, true, true, 'X');
+        assert_eq!(
+            state.axis_homed,
+            Workspace {
+                x: false,
+                y: false,
+                z: true
+            }
+        );
+
+        // Only the requested axes are unflagged.
+        let mut state = homed(10., 10., 10.);
+        state.homing_failed(false, false, true, 'Z');
+        assert_eq!(
+            state.axis_homed,
+            Workspace {
+                x: true,
+                y: true,
+                z: false
+            }
+        );
+    }
+
+    #[test]
+    fn travel_limits() {
+        let mut state = homed(10., 10., 10.);
+        let before = state.clone();
+        assert_eq!(
+            state.apply(&linear(None, Some(TRAVEL_MAX[1] + 1.), None)),
+            Err(Error::OutOfLimits {
+                axis: 'Y',
+                position: TRAVEL_MAX[1] + 1.,
+            })
+        );
+        assert_eq!(
+            state.apply(&linear(None, None, Some(-1.))),
+            Err(Error::OutOfLimits {
+                axis: 'Z',
+                position: -1.,
+            })
+        );
+        // Refused moves leave the state untouched.
+        assert_eq!(state.position, before.position);
+        assert_eq!(state.motion_mode, before.motion_mode);
+
+        state
+            .apply(&linear(None, Some(TRAVEL_MAX[1]), None))
+            .unwrap();
+        state.apply(&Command::SetSoftLimits(Some(false))).unwrap();
+        state.apply(&linear(None, None, Some(-1.))).unwrap();
+        assert_eq!(state.position.z, -1.);
+    }
+
+    #[test]
+    fn travel_limits_only_apply_to_homed_axes() {
+        let mut state = homed(10., 10., 10.);
+        state.unhome(true, false, false);
+        state
+            .apply(&Command::SetPositioning(Positioning::Relative))
+            .unwrap();
+        state.apply(&linear(Some(-20.), None, None)).unwrap();
+        assert_eq!(state.position.x, -10.);
+        assert!(state.apply(&linear(None, Some(-20.), None)).is_err());
+    }
+
+    #[test]
+    fn clipped_travel_limits() {
+        let mut state = homed(10., 10., 10.);
+        state.soft_limit_policy = SoftLimitPolicy::Clip;
+        assert_eq!(
+            state.apply(&linear(Some(5.), Some(TRAVEL_MAX[1] + 50.), None)),
+            Ok(Some(Command::LinearMove {
+                move_type: MoveType::Linear,
+                x: Some(5.),
+                y: Some(TRAVEL_MAX[1]),
+                z: Some(10.),
+                e: Some(0.),
+            }))
+        );
+
+        // Relative moves and work offsets are clipped in machine coordinates.
+        state
+            .apply(&Command::SetPositioning(Positioning::Relative))
+            .unwrap();
+        state.apply(&linear(Some(-50.), None, None)).unwrap();
+        assert_eq!(state.position.x, TRAVEL_MIN[0]);
+        state
+            .apply(&Command::SetPositioning(Positioning::Absolute))
+            .unwrap();
+        state
+            .apply(&Command::SetPosition {
+                x: Some(100.),
+                y: None,
+                z: None,
+                e: None,
+            })
+            .unwrap();
+        state.apply(&linear(Some(-10.), None, None)).unwrap();
+        assert_eq!(state.position.x, TRAVEL_MIN[0]);
+        assert_eq!(state.work_position().x, 100.);
+
+        // Arcs are still refused rather than distorted, this full circle crosses X's lower limit.
+        let arc = Command::ArcMove {
+            direction: ArcDirection::Clockwise,
+            x: Some(100.),
+            y: Some(TRAVEL_MAX[1]),
+            z: None,
+            e: None,
+            i: Some(0.),
+            j: Some(-5.),
+            k: None,
+            r: None,
+        };
+        assert!(matches!(
+            state.apply(&arc),
+            Err(Error::OutOfLimits { axis: 'X', .. })
+        ));
+
+        // Neither the unhomed axes nor the moves made without soft limits are clipped.
+        state.unhome(false, false, true);
+        state
+            .apply(&Command::SetPositioning(Positioning::Relative))
+            .unwrap();
+        state.apply(&linear(None, None, Some(-15.))).unwrap();
+        assert_eq!(state.position.z, -5.);
+        state.apply(&Command::SetSoftLimits(Some(false))).unwrap();
+        state.apply(&linear(None, Some(50.), None)).unwrap();
+        assert_eq!(state.position.y, TRAVEL_MAX[1] + 50.);
+    }
+}
@@ -0,0 +1,504 @@
+//! Step pulse generation.
+//!
+//! Motion reaches the [`StepGenerator`] as [`Segment`]s sent through a lock-free
+//! [`SegmentQueue`]. The generator runs from a timer interrupt: each call to
+//! [`StepGenerator::tick`] performs one step event of the current segment and returns the delay
+//! until the next one, which the platform programs in its timer. The generator goes idle when the
+//! queue runs dry and the platform restarts the timer when a [`SegmentSink`] pushes a new segment.
+//!
+//! Within a segment, the steps of each axis are spread evenly over the step events using
+//! Bresenham's algorithm.
+//!
+//! Seek segments, used for homing and probing, are cut short as soon as the switch they look for
+//! triggers, which the sink reports once the generator went idle.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::Poll;
+
+use futures::future;
+use futures::task::AtomicWaker;
+
+use crate::config::{AXIS_COUNT, ENDSTOP_TRIGGERED_HIGH, PROBE_TRIGGERED_HIGH};
+use crate::ring_buffer::RingBuffer;
+
+/// Frequency of the timer driving the step generator.
+pub const STEP_TIMER_HZ: u32 = 1_000_000;
+
+/// Shortest delay between two step events, in timer ticks.
+pub const MIN_STEP_INTERVAL: u32 = 10;
+
+/// Number of segments that can wait for the step generator.
+const SEGMENT_QUEUE_DEPTH: usize = 32;
+
+/// Stored instead of a number of step events when a seek segment ran all its steps.
+const NOT_TRIGGERED: u32 = u32::MAX;
+
+/// Input stopping a seek segment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Switch {
+    /// The endstop of the X, Y or Z axis.
+    Endstop(usize),
+    Probe,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    /// Steps to perform on each axis, their sign giving the direction.
+    ///
+    /// One step event, moving any number of axes by one step, happens every `interval` timer
+    /// ticks. A segment without any step waits for a single interval.
+    Move {
+        steps: [i32; AXIS_COUNT],
+        interval: u32,
+    },
+    /// Like [`Segment::Move`], stopping before the first step event that finds `switch`
+    /// triggered.
+    Seek {
+        steps: [i32; AXIS_COUNT],
+        interval: u32,
+        switch: Switch,
+    },
+    /// Enables or disables the stepper drivers.
+    Enable(bool),
+}
+
+impl Segment {
+    /// A move performing `steps` in `duration` timer ticks, at a constant rate.
+    pub fn linear(steps: [i32; AXIS_COUNT], duration: u32) -> Self {
+        let events = step_events(&steps);
+        Segment::Move {
+            steps,
+            interval: (duration / events).max(MIN_STEP_INTERVAL),
+        }
+    }
+
+    /// A move performing at most `steps` in `duration` timer ticks, at a constant rate, until
+    /// `switch` triggers.
+    pub fn seek(steps: [i32; AXIS_COUNT], duration: u32, switch: Switch) -> Self {
+        let events = step_events(&steps);
+        Segment::Seek {
+            steps,
+            interval: (duration / events).max(MIN_STEP_INTERVAL),
+            switch,
+        }
+    }
+}
+
+/// Number of step events needed to perform `steps`.
+pub fn step_events(steps: &[i32; AXIS_COUNT]) -> u32 {
+    steps
+        .iter()
+        .map(|s| s.unsigned_abs())
+        .max()
+        .unwrap_or(0)
+        .max(1)
+}
+
+pub struct SegmentQueue {
+    buffer: RingBuffer<Segment, SEGMENT_QUEUE_DEPTH>,
+    /// Whether the generator is executing a segment.
+    busy: AtomicBool,
+    /// Step events performed by the last seek segment before its switch triggered.
+    triggered: AtomicU32,
+    /// task waiting for room in the queue or for the generator to go idle
+    waker: AtomicWaker,
+}
+
+impl SegmentQueue {
+    pub const fn new() -> Self {
+        Self {
+            buffer: RingBuffer::new(),
+            busy: AtomicBool::new(false),
+            triggered: AtomicU32::new(NOT_TRIGGERED),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// The producer side of the queue.
+    ///
+    /// `start` is called whenever a segment was queued and must make sure the generator's timer
+    /// is running. There must be only one sink per queue.
+    pub fn sink(&'static self, start: fn()) -> SegmentSink {
+        SegmentSink { queue: self, start }
+    }
+
+    fn pop(&self) -> Option<Segment> {
+        // Flag the generator busy before the segment leaves the queue so that it never looks idle
+        // while there is work left.
+        self.busy.store(true, Ordering::Release);
+        let segment = self.buffer.pop();
+        if segment.is_none() {
+            self.busy.store(false, Ordering::Release);
+        }
+        self.waker.wake();
+        segment
+    }
+}
+
+pub struct SegmentSink {
+    queue: &'static SegmentQueue,
+    start: fn(),
+}
+
+impl SegmentSink {
+    /// Resolves once there is room for at least one more segment.
+    pub async fn room(&self) {
+        future::poll_fn(|cx| {
+            self.queue.waker.register(cx.waker());
+            if self.queue.buffer.is_full() {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await
+    }
+
+    /// Queues a segment, waiting for room if needed.
+    pub async fn push(&mut self, segment: Segment) {
+        self.room().await;
+        self.queue
+            .buffer
+            .push(segment)
+            .unwrap_or_else(|_| unreachable!());
+        (self.start)();
+    }
+
+    /// Resolves once every queued segment has been executed.
+    pub async fn idle(&mut self) {
+        future::poll_fn(|cx| {
+            self.queue.waker.register(cx.waker());
+            if self.queue.buffer.is_empty() && !self.queue.busy.load(Ordering::Acquire) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Number of step events the last seek segment performed before its switch triggered, if it
+    /// did. Only meaningful once idle.
+    pub fn triggered(&self) -> Option<u32> {
+        match self.queue.triggered.load(Ordering::Acquire) {
+            NOT_TRIGGERED => None,
+            events => Some(events),
+        }
+    }
+}
+
+/// Pins of the stepper drivers, of the endstops and of the probe.
+pub trait StepperPins {
+    /// Drives the DIR pins, `true` being the positive direction.
+    fn set_directions(&mut self, forward: [bool; AXIS_COUNT]);
+    /// Raises the STEP pins of the given axes.
+    fn set_steps(&mut self, step: [bool; AXIS_COUNT]);
+    /// Lowers all the STEP pins.
+    fn clear_steps(&mut self);
+    fn set_enabled(&mut self, enabled: bool);
+    /// Reads the endstop pin of the X, Y or Z `axis`, `true` being high.
+    fn endstop(&mut self, axis: usize) -> bool;
+    /// Reads the probe pin, `true` being high.
+    fn probe(&mut self) -> bool;
+}
+
+/// For boards whose drivers are not supported yet, timing is still honoured.
+impl StepperPins for () {
+    fn set_directions(&mut self, _: [bool; AXIS_COUNT]) {}
+    fn set_steps(&mut self, _: [bool; AXIS_COUNT]) {}
+    fn clear_steps(&mut self) {}
+    fn set_enabled(&mut self, _: bool) {}
+    fn endstop(&mut self, axis: usize) -> bool {
+        !ENDSTOP_TRIGGERED_HIGH[axis]
+    }
+    fn probe(&mut self) -> bool {
+        !PROBE_TRIGGERED_HIGH
+    }
+}
+
+/// Drives `pin` high or low, ignoring errors as GPIOs don't fail.
+#[allow(dead_code)]
+pub fn set_pin<P: embedded_hal::digital::v2::OutputPin>(pin: &mut P, high: bool) {
+    let _ = if high { pin.set_high() } else { pin.set_low() };
+}
+
+/// Reads `pin`, ignoring errors as GPIOs don't fail.
+#[allow(dead_code)]
+pub fn read_pin<P: embedded_hal::digital::v2::InputPin>(pin: &P) -> bool {
+    pin.is_high().unwrap_or(false)
+}
+
+/// The segment being executed.
+struct Current {
+    /// Number of steps of each axis, without their sign.
+    steps: [u32; AXIS_COUNT],
+    /// Bresenham's error accumulators.
+    counters: [u32; AXIS_COUNT],
+    /// Step events left.
+    events_left: u32,
+    events: u32,
+    interval: u32,
+    /// Input stopping the segment.
+    switch: Option<Switch>,
+}
+
+pub struct StepGenerator<P> {
+    pins: P,
+    queue: &'static SegmentQueue,
+    current: Option<Current>,
+}
+
+impl<P: StepperPins> StepGenerator<P> {
+    pub fn new(pins: P, queue: &'static SegmentQueue) -> Self {
+        Self {
+            pins,
+            queue,
+            current: None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn pins_mut(&mut self) -> &mut P {
+        &mut self.pins
+    }
+
+    fn start(&mut self, steps: [i32; AXIS_COUNT], interval: u32, switch: Option<Switch>) {
+        let mut forward = [true; AXIS_COUNT];
+        let mut abs_steps = [0; AXIS_COUNT];
+        for ((f, a), s) in forward.iter_mut().zip(abs_steps.iter_mut()).zip(&steps) {
+            *f = *s >= 0;
+            *a = s.unsigned_abs();
+        }
+        self.pins.set_directions(forward);
+
+        let events = step_events(&steps);
+        self.current = Some(Current {
+            steps: abs_steps,
+            counters: [events / 2; AXIS_COUNT],
+            events_left: events,
+            events,
+            interval,
+            switch,
+        });
+    }
+
+    /// Performs the next step event.
+    ///
+    /// To be called from the timer interrupt. Returns the number of timer ticks until the next
+    /// call or `None` once idle, in which case the timer should be stopped until the sink starts
+    /// it again.
+    pub fn tick(&mut self) -> Option<u32> {
+        // The STEP pins were raised a whole interval ago, which is plenty for any driver.
+        self.pins.clear_steps();
+
+        while self.current.is_none() {
+            match self.queue.pop()? {
+                Segment::Enable(enabled) => self.pins.set_enabled(enabled),
+                Segment::Move { steps, interval } => self.start(steps, interval, None),
+                Segment::Seek {
+                    steps,
+                    interval,
+                    switch,
+                } => {
+                    self.queue.triggered.store(NOT_TRIGGERED, Ordering::Release);
+                    self.start(steps, interval, Some(switch));
+                }
+            }
+        }
+        let current = self.current.as_mut().unwrap_or_else(|| unreachable!());
+
+        let triggered = match current.switch {
+            Some(Switch::Endstop(axis)) => self.pins.endstop(axis) == ENDSTOP_TRIGGERED_HIGH[axis],
+            Some(Switch::Probe) => self.pins.probe() == PROBE_TRIGGERED_HIGH,
+            None => false,
+        };
+        if triggered {
+            let performed = current.events - current.events_left;
+            self.queue.triggered.store(performed, Ordering::Release);
+            self.current = None;
+            return Some(MIN_STEP_INTERVAL);
+        }
+
+        let mut step = [false; AXIS_COUNT];
+        for ((s, counter), steps) in step
+            .iter_mut()
+            .zip(current.counters.iter_mut())
+            .zip(current.steps.iter())
+        {
+            *counter += steps;
+            if *counter >= current.events {
+                *counter -= current.events;
+                *s = true;
+            }
+        }
+        self.pins.set_steps(step);
+
+        let interval = current.interval;
+        current.events_left -= 1;
+        if current.events_left == 0 {
+            self.current = None;
+        }
+        Some(interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    /// Records the step events, its endstops trigger once `endstop_after` step events happened.
+    #[derive(Default)]
+    struct Pins {
+        forward: [bool; AXIS_COUNT],
+        steps: Vec<[bool; AXIS_COUNT]>,
+        enabled: bool,
+        endstop_after: Option<usize>,
+    }
+
+    impl StepperPins for Pins {
+        fn set_directions(&mut self, forward: [bool; AXIS_COUNT]) {
+            self.forward = forward;
+        }
+        fn set_steps(&mut self, step: [bool; AXIS_COUNT]) {
+            self.steps.push(step);
+        }
+        fn clear_steps(&mut self) {}
+        fn set_enabled(&mut self, enabled: bool) {
+            self.enabled = enabled;
+        }
+        fn endstop(&mut self, axis: usize) -> bool {
+            let triggered = matches!(self.endstop_after, Some(n) if self.steps.len() >= n);
+            triggered == ENDSTOP_TRIGGERED_HIGH[axis]
+        }
+        fn probe(&mut self) -> bool {
+            !PROBE_TRIGGERED_HIGH
+        }
+    }
+
+    fn generator(segments: &[Segment]) -> (StepGenerator<Pins>, SegmentSink) {
+        let queue: &'static SegmentQueue = Box::leak(Box::new(SegmentQueue::new()));
+        let mut sink = queue.sink(|| {});
+        for segment in segments {
+            sink.push(*segment).now_or_never().unwrap();
+        }
+        (StepGenerator::new(Pins::default(), queue), sink)
+    }
+
+    /// Ticks until idle, returning the delays between the step events.
+    fn run(generator: &mut StepGenerator<Pins>) -> Vec<u32> {
+        core::iter::from_fn(|| generator.tick()).collect()
+    }
+
+    /// Step events at which `axis` steps.
+    fn events(pins: &Pins, axis: usize) -> Vec<usize> {
+        (0..pins.steps.len())
+            .filter(|i| pins.steps[*i][axis])
+            .collect()
+    }
+
+    #[test]
+    fn spreads_the_steps_evenly() {
+        let (mut generator, _) = generator(&[Segment::Move {
+            steps: [8, -4, 3, 0],
+            interval: 100,
+        }]);
+
+        assert_eq!(run(&mut generator), [100; 8]);
+        let pins = generator.pins_mut();
+        assert_eq!(pins.forward, [true, false, true, true]);
+        assert_eq!(events(pins, 0), [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(events(pins, 1), [0, 2, 4, 6]);
+        assert_eq!(events(pins, 2), [1, 3, 6]);
+        assert_eq!(events(pins, 3), []);
+    }
+
+    #[test]
+    fn runs_each_segment_at_its_own_rate() {
+        let (mut generator, _) = generator(&[
+            Segment::linear([0, 3, 0, 0], 600),
+            Segment::linear([0; AXIS_COUNT], 500),
+            Segment::linear([-2, 0, 0, 1], 50),
+        ]);
+
+        assert_eq!(run(&mut generator), [200, 200, 200, 500, 25, 25]);
+        let pins = generator.pins_mut();
+        // The segment without steps waits without stepping.
+        assert_eq!(events(pins, 1), [0, 1, 2]);
+        assert_eq!(pins.steps[3], [false; AXIS_COUNT]);
+        assert_eq!(events(pins, 0), [4, 5]);
+        assert_eq!(events(pins, 3), [4]);
+        assert_eq!(pins.forward, [false, true, true, true]);
+    }
+
+    #[test]
+    fn clamps_the_step_rate() {
+        assert_eq!(
+            Segment::linear([1000, 10, 0, 0], 1000),
+            Segment::Move {
+                steps: [1000, 10, 0, 0],
+                interval: MIN_STEP_INTERVAL,
+            }
+        );
+        assert_eq!(
+            Segment::seek([0, 0, -500, 0], 0, Switch::Endstop(2)),
+            Segment::Seek {
+                steps: [0, 0, -500, 0],
+                interval: MIN_STEP_INTERVAL,
+                switch: Switch::Endstop(2),
+            }
+        );
+    }
+
+    #[test]
+    fn seeking_stops_at_the_switch() {
+        let seek = Segment::seek([0, 0, -100, 0], 10_000, Switch::Endstop(2));
+        let (mut generator, mut sink) = generator(&[seek]);
+        generator.pins_mut().endstop_after = Some(30);
+
+        let intervals = run(&mut generator);
+        assert_eq!(intervals.len(), 31);
+        assert!(intervals[..30].iter().all(|i| *i == 100));
+        assert_eq!(intervals[30], MIN_STEP_INTERVAL);
+        assert_eq!(events(generator.pins_mut(), 2).len(), 30);
+        assert_eq!(sink.triggered(), Some(30));
+
+        // A seek that runs all its steps doesn't report the previous one.
+        generator.pins_mut().endstop_after = None;
+        sink.push(seek).now_or_never().unwrap();
+        assert_eq!(run(&mut generator), [100; 100]);
+        assert_eq!(sink.triggered(), None);
+    }
+
+    #[test]
+    fn sink_waits_for_the_generator() {
+        let (mut generator, mut sink) = generator(&[]);
+        assert!(sink.idle().now_or_never().is_some());
+
+        sink.push(Segment::Enable(true)).now_or_never().unwrap();
+        sink.push(Segment::linear([2, 0, 0, 0], 200))
+            .now_or_never()
+            .unwrap();
+        assert!(sink.idle().now_or_never().is_none());
+
+        // Enabling the drivers takes no step event of its own.
+        assert_eq!(generator.tick(), Some(100));
+        assert!(generator.pins_mut().enabled);
+        assert!(sink.idle().now_or_never().is_none());
+        assert_eq!(generator.tick(), Some(100));
+        // The last step event still has to wait for its interval.
+        assert!(sink.idle().now_or_never().is_none());
+        assert_eq!(generator.tick(), None);
+        assert!(sink.idle().now_or_never().is_some());
+
+        for _ in 0..SEGMENT_QUEUE_DEPTH {
+            assert!(sink.room().now_or_never().is_some());
+            sink.push(Segment::Enable(false)).now_or_never().unwrap();
+        }
+        assert!(sink.room().now_or_never().is_none());
+        assert_eq!(generator.tick(), None);
+        assert!(!generator.pins_mut().enabled);
+        assert!(sink.room().now_or_never().is_some());
+        assert!(sink.idle().now_or_never().is_some());
+    }
+}
@@ -0,0 +1,463 @@
+//! Monotonic time and async timers.
+//!
+//! Time is counted in ticks of `1/TICK_HZ` seconds by [`tick`] which the platform calls
+//! periodically (from the SysTick exception on target, from a thread on the host).
+//!
+//! Pending timers are kept in a small table of slots that [`tick`] scans to wake the tasks whose
+//! deadline is reached. Everything is lock-free so that [`tick`] can run in interrupt context.
+//!
+//! The time base and its slots make up a [`Clock`]. The firmware runs on a single static one. In
+//! the host tests, each thread has its own virtual clock instead, which the mock platform advances
+//! whenever the firmware has nothing else to do (see [`skip_to_next_deadline`]).
+
+use core::future::Future;
+use core::ops::Add;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use futures::task::AtomicWaker;
+
+/// Frequency at which [`tick`] is called.
+pub const TICK_HZ: u32 = 1_000;
+
+/// Maximum number of timers pending at the same time.
+const SLOT_COUNT: usize = 8;
+
+#[cfg(not(test))]
+static CLOCK: Clock = Clock::new();
+
+#[cfg(not(test))]
+fn clock() -> &'static Clock {
+    &CLOCK
+}
+
+/// The clock of the current thread, the tests run concurrently.
+#[cfg(test)]
+fn clock() -> &'static Clock {
+    std::thread_local! {
+        static CLOCK: &'static Clock = Box::leak(Box::new(Clock::new()));
+    }
+    CLOCK.with(|clock| *clock)
+}
+
+struct Slot {
+    used: AtomicBool,
+    deadline: AtomicU32,
+    waker: AtomicWaker,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            used: AtomicBool::new(false),
+            deadline: AtomicU32::new(0),
+            waker: AtomicWaker::new(),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot::new();
+
+/// A time base along with its pending timers.
+struct Clock {
+    now: AtomicU32,
+    slots: [Slot; SLOT_COUNT],
+}
+
+impl Clock {
+    const fn new() -> Self {
+        Self {
+            now: AtomicU32::new(0),
+            slots: [EMPTY_SLOT; SLOT_COUNT],
+        }
+    }
+
+    /// Advances the time by one tick and wakes the timers that expired.
+    fn tick(&self) {
+        let now = Instant(self.now.fetch_add(1, Ordering::AcqRel).wrapping_add(1));
+        for slot in self.slots.iter() {
+            if slot.used.load(Ordering::Acquire)
+                && Instant(slot.deadline.load(Ordering::Acquire)).is_reached(now)
+            {
+                slot.waker.wake();
+            }
+        }
+    }
+
+    fn now(&self) -> Instant {
+        Instant(self.now.load(Ordering::Acquire))
+    }
+
+    /// Number of ticks until the nearest deadline of the pending timers, if any.
+    #[cfg(test)]
+    fn until_next_deadline(&self) -> Option<u32> {
+        let now = self.now();
+        self.slots
+            .iter()
+            .filter(|slot| slot.used.load(Ordering::Acquire))
+            .map(|slot| {
+                let ticks = slot.deadline.load(Ordering::Acquire).wrapping_sub(now.0) as i32;
+                ticks.max(0) as u32
+            })
+            .min()
+    }
+
+    /// A timer of this clock, resolving once `deadline` is reached.
+    fn timer_at(&'static self, deadline: Instant) -> Timer {
+        Timer {
+            clock: self,
+            deadline,
+            slot: None,
+        }
+    }
+
+    /// A ticker of this clock, resolving every `period` from now on.
+    fn ticker(&'static self, period: Duration) -> Ticker {
+        Ticker {
+            clock: self,
+            next: self.now() + period,
+            period,
+        }
+    }
+}
+
+/// Advances the time by one tick and wakes the timers that expired.
+pub fn tick() {
+    clock().tick()
+}
+
+/// Advances the time straight to the nearest deadline of the pending timers, waking them.
+///
+/// Returns `false` if no timer is pending.
+#[cfg(test)]
+pub fn skip_to_next_deadline() -> bool {
+    let clock = clock();
+    match clock.until_next_deadline() {
+        Some(ticks) => {
+            // Even an expired timer gets woken again by a tick.
+            for _ in 0..ticks.max(1) {
+                clock.tick();
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// A point in time, wraps around after about 49 days.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instant(u32);
+
+impl Instant {
+    pub fn now() -> Self {
+        clock().now()
+    }
+
+    /// Time elapsed since `self`.
+    #[allow(dead_code)]
+    pub fn elapsed(&self) -> Duration {
+        let ticks = Self::now().0.wrapping_sub(self.0);
+        Duration::from_micros(u64::from(ticks) * 1_000_000 / u64::from(TICK_HZ))
+    }
+
+    fn is_reached(self, now: Instant) -> bool {
+        now.0.wrapping_sub(self.0) as i32 >= 0
+    }
+}
+
+/// Number of ticks in `duration`, rounded up so that a timer never fires early, and capped to
+/// the half of the counter's range that is ahead of the current time.
+fn ticks(duration: Duration) -> u32 {
+    let ticks = (duration.as_micros() * u128::from(TICK_HZ)).div_ceil(1_000_000);
+    ticks.min(i32::MAX as u128) as u32
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Self::Output {
+        Self(self.0.wrapping_add(ticks(rhs)))
+    }
+}
+
+/// A future resolving once its deadline is reached.
+pub struct Timer {
+    clock: &'static Clock,
+    deadline: Instant,
+    slot: Option<&'static Slot>,
+}
+
+impl Timer {
+    pub fn at(deadline: Instant) -> Self {
+        clock().timer_at(deadline)
+    }
+
+    pub fn after(duration: Duration) -> Self {
+        Self::at(Instant::now() + duration)
+    }
+
+    fn claim_slot(&mut self) -> Option<&'static Slot> {
+        if self.slot.is_none() {
+            self.slot = self.clock.slots.iter().find(|slot| {
+                slot.used
+                    .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            });
+            if let Some(slot) = self.slot {
+                slot.deadline.store(self.deadline.0, Ordering::Release);
+            }
+        }
+        self.slot
+    }
+
+    fn release_slot(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            slot.used.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        if !this.deadline.is_reached(this.clock.now()) {
+            match this.claim_slot() {
+                Some(slot) => slot.waker.register(cx.waker()),
+                // All slots are taken, fall back to polling.
+                None => cx.waker().wake_by_ref(),
+            }
+            // The deadline may have been reached before the waker got registered.
+            if !this.deadline.is_reached(this.clock.now()) {
+                return Poll::Pending;
+            }
+        }
+        this.release_slot();
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.release_slot();
+    }
+}
+
+/// Resolves at a fixed period, without drifting.
+///
+/// Not used yet, meant for periodic work such as sampling the temperatures or reporting them.
+#[allow(dead_code)]
+pub struct Ticker {
+    clock: &'static Clock,
+    next: Instant,
+    period: Duration,
+}
+
+#[allow(dead_code)]
+impl Ticker {
+    pub fn every(period: Duration) -> Self {
+        clock().ticker(period)
+    }
+
+    /// Waits for the next period to elapse.
+    ///
+    /// Periods that already elapsed resolve right away, so that a late caller catches up.
+    pub async fn next(&mut self) {
+        self.clock.timer_at(self.next).await;
+        self.next = self.next + self.period;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    /// A clock of its own, whatever ran on this thread before.
+    fn new_clock() -> &'static Clock {
+        Box::leak(Box::new(Clock::new()))
+    }
+
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::Release);
+        }
+    }
+
+    /// A timer along with the flag its waker raises.
+    struct Polled {
+        timer: Timer,
+        woken: Arc<Flag>,
+    }
+
+    impl Polled {
+        fn new(timer: Timer) -> Self {
+            Self {
+                timer,
+                woken: Arc::default(),
+            }
+        }
+
+        fn woken(&self) -> bool {
+            self.woken.0.swap(false, Ordering::AcqRel)
+        }
+
+        fn poll(&mut self) -> Poll<()> {
+            let waker = Waker::from(Arc::clone(&self.woken));
+            Pin::new(&mut self.timer).poll(&mut Context::from_waker(&waker))
+        }
+    }
+
+    #[test]
+    fn expires_at_its_deadline() {
+        let clock = new_clock();
+        let mut timer = Polled::new(clock.timer_at(clock.now() + Duration::from_millis(3)));
+        assert_eq!(timer.poll(), Poll::Pending);
+        for _ in 0..2 {
+            clock.tick();
+            assert!(!timer.woken());
+        }
+        clock.tick();
+        assert!(timer.woken());
+        assert_eq!(timer.poll(), Poll::Ready(()));
+        assert!(clock
+            .slots
+            .iter()
+            .all(|slot| !slot.used.load(Ordering::Acquire)));
+    }
+
+    #[test]
+    fn never_expires_early() {
+        let clock = new_clock();
+        // 1.5 ticks
+        let mut timer = Polled::new(clock.timer_at(clock.now() + Duration::from_micros(1_500)));
+        clock.tick();
+        assert_eq!(timer.poll(), Poll::Pending);
+        clock.tick();
+        assert!(timer.woken());
+        assert_eq!(timer.poll(), Poll::Ready(()));
+
+        let mut timer = Polled::new(clock.timer_at(clock.now()));
+        assert_eq!(timer.poll(), Poll::Ready(()));
+    }
+
+    #[test]
+    fn expire_in_order() {
+        let clock = new_clock();
+        let start = clock.now();
+        let mut timers: Vec<_> = [5, 2, 8]
+            .iter()
+            .map(|ms| Polled::new(clock.timer_at(start + Duration::from_millis(*ms))))
+            .collect();
+        for timer in &mut timers {
+            assert_eq!(timer.poll(), Poll::Pending);
+        }
+        let mut expired = Vec::new();
+        for now in 1..=8 {
+            clock.tick();
+            for (i, timer) in timers.iter_mut().enumerate() {
+                if timer.woken() {
+                    assert_eq!(timer.poll(), Poll::Ready(()));
+                    expired.push((i, now));
+                }
+            }
+        }
+        assert_eq!(expired, [(1, 2), (0, 5), (2, 8)]);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let clock = new_clock();
+        clock.now.store(u32::MAX - 1, Ordering::Release);
+        let mut timer = Polled::new(clock.timer_at(clock.now() + Duration::from_millis(3)));
+        assert_eq!(timer.poll(), Poll::Pending);
+        clock.tick();
+        clock.tick();
+        assert_eq!(clock.now(), Instant(0));
+        assert!(!timer.woken());
+        clock.tick();
+        assert!(timer.woken());
+        assert_eq!(timer.poll(), Poll::Ready(()));
+    }
+
+    #[test]
+    fn polls_when_out_of_slots() {
+        let clock = new_clock();
+        let deadline = clock.now() + Duration::from_millis(1);
+        let mut timers: Vec<_> = (0..=SLOT_COUNT)
+            .map(|_| Polled::new(clock.timer_at(deadline)))
+            .collect();
+        for timer in &mut timers {
+            assert_eq!(timer.poll(), Poll::Pending);
+        }
+        // The timer left without a slot asks to be polled again straight away.
+        let (last, slotted) = timers.split_last_mut().unwrap_or_else(|| unreachable!());
+        assert!(last.woken());
+        assert!(slotted.iter().all(|timer| !timer.woken()));
+
+        // Dropping a timer frees its slot.
+        timers.truncate(1);
+        assert_eq!(timers[0].poll(), Poll::Pending);
+        let mut timer = Polled::new(clock.timer_at(deadline));
+        assert_eq!(timer.poll(), Poll::Pending);
+        assert!(!timer.woken());
+        clock.tick();
+        assert!(timer.woken() && timers[0].woken());
+    }
+
+    #[test]
+    fn ticks_without_drifting() {
+        let clock = new_clock();
+        let mut ticker = clock.ticker(Duration::from_millis(3));
+        let woken = Arc::new(Flag::default());
+        let waker = Waker::from(Arc::clone(&woken));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut expired = Vec::new();
+        for now in 1..=12 {
+            clock.tick();
+            // Polled late once, at 7 instead of 6.
+            if now == 6 {
+                continue;
+            }
+            loop {
+                let next = ticker.next();
+                pin_utils::pin_mut!(next);
+                if next.poll(&mut cx).is_pending() {
+                    break;
+                }
+                expired.push(now);
+            }
+        }
+        // The late period doesn't delay the next one.
+        assert_eq!(expired, [3, 7, 9, 12]);
+    }
+
+    #[test]
+    fn skips_to_the_next_deadline() {
+        // This thread's clock.
+        let start = Instant::now();
+        assert!(!skip_to_next_deadline());
+        let mut first = Polled::new(Timer::after(Duration::from_millis(20)));
+        let mut second = Polled::new(Timer::after(Duration::from_millis(5)));
+        assert_eq!(first.poll(), Poll::Pending);
+        assert_eq!(second.poll(), Poll::Pending);
+
+        assert!(skip_to_next_deadline());
+        assert_eq!(start.elapsed(), Duration::from_millis(5));
+        assert!(second.woken() && !first.woken());
+        assert_eq!(second.poll(), Poll::Ready(()));
+
+        assert!(skip_to_next_deadline());
+        assert_eq!(start.elapsed(), Duration::from_millis(20));
+        assert_eq!(first.poll(), Poll::Ready(()));
+        assert!(!skip_to_next_deadline());
+    }
+}
@@ -0,0 +1,87 @@
+//! Runs G-code scripts through the firmware built for `platform-sim`, checking its responses and
+//! the step pulses it generated.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Output of a run of the firmware.
+struct Run {
+    /// Responses, line by line, the firmware's banner excluded.
+    responses: Vec<String>,
+    /// Number of steps generated per axis and direction (eg. `X+`).
+    steps: HashMap<String, usize>,
+}
+
+/// Runs `script` through the firmware, in an environment made of `vars` only as far as the
+/// simulation is concerned.
+fn run(name: &str, script: &str, vars: &[(&str, &str)]) -> Run {
+    let log = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.steps", name));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_printer-firmware"))
+        .env_remove("SIM_POSITION")
+        .env_remove("SIM_BED")
+        .env("SIM_STEP_LOG", &log)
+        .envs(vars.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start the firmware");
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(script.as_bytes()).unwrap();
+    // Closing the input lets the firmware exit once everything is executed.
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let output = String::from_utf8(output.stdout).unwrap();
+    let mut lines = output.lines().map(String::from);
+    assert!(lines.next().unwrap().starts_with("Rusty "));
+    let mut steps = HashMap::new();
+    for line in fs::read_to_string(&log).unwrap().lines() {
+        let event = line.split(' ').nth(1).unwrap();
+        *steps.entry(event.to_string()).or_insert(0) += 1;
+    }
+    Run {
+        responses: lines.collect(),
+        steps,
+    }
+}
+
+#[test]
+fn moves_and_reports() {
+    let run = run(
+        "moves_and_reports",
+        "M115\nG91\nG1 X10 Y-5 F1200\nM114\n",
+        &[],
+    );
+    assert!(run.responses[0].ends_with("HW:sim"));
+    assert_eq!(run.responses[1], "SYSCLK:1000000000");
+    // How full the buffer got depends on how fast the parser kept up.
+    assert!(run.responses[2].starts_with("RX_BUFFER:128 HIGH_WATER_MARK:"));
+    assert!(run.responses[2].ends_with(" DROPPED:0"));
+    assert_eq!(
+        run.responses[3..],
+        ["ok", "ok", "ok", "X:10.00 Y:-5.00 Z:0.00 E:0.00", "ok"]
+    );
+    assert_eq!(run.steps["X+"], 800);
+    assert_eq!(run.steps["Y-"], 400);
+    assert!(!run.steps.contains_key("X-"));
+    assert!(!run.steps.contains_key("Z+"));
+}
+
+#[test]
+fn reports_errors_and_carries_on() {
+    let run = run("reports_errors", "G1 X10\nM114\n", &[]);
+    assert_eq!(
+        run.responses,
+        [
+            "error: NotHomed('X')",
+            "ok",
+            "X:0.00 Y:0.00 Z:0.00 E:0.00",
+            "ok"
+        ]
+    );
+    assert!(run.steps.keys().all(|event| !event.ends_with(['+', '-'])));
+}